@@ -1,8 +1,12 @@
 #include "lwext4/include/ext4.h"
+#include "lwext4/include/ext4_crc32.h"
 #include "lwext4/include/ext4_dir.h"
+#include "lwext4/include/ext4_extent.h"
 #include "lwext4/include/ext4_fs.h"
 #include "lwext4/include/ext4_inode.h"
 #include "lwext4/include/ext4_mbr.h"
 #include "lwext4/include/ext4_mkfs.h"
+#include "lwext4/include/ext4_super.h"
+#include "lwext4/include/ext4_xattr.h"
 
 #include "lwext4/fs_test/common/test_lwext4.h"
@@ -0,0 +1,95 @@
+use alloc::vec::Vec;
+
+use crate::{BlockDevice, Ext4Filesystem, Ext4Result, InodeType, SystemHal};
+
+/// Inconsistency found by [`Ext4Filesystem::check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckError {
+    /// The superblock free block count disagrees with the block bitmaps.
+    FreeBlocksMismatch { superblock: u64, bitmap: u64 },
+    /// The superblock free inode count disagrees with the inode bitmaps.
+    FreeInodesMismatch { superblock: u32, bitmap: u32 },
+    /// Directory without a `.` entry.
+    MissingDot(u32),
+    /// Directory without a `..` entry.
+    MissingDotDot(u32),
+    /// Allocated inode with no links left.
+    OrphanInode(u32),
+}
+
+/// Result of [`Ext4Filesystem::check`].
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    pub errors: Vec<CheckError>,
+}
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
+    /// Checks the mounted filesystem for common inconsistencies and reports
+    /// every one found, without repairing anything.
+    ///
+    /// The free counts are compared with the bitmaps, every directory must
+    /// have `.` and `..` entries, and allocated inodes must have links. Only
+    /// errors that prevent the scan itself are returned as `Err`.
+    pub fn check(&mut self) -> Ext4Result<CheckReport> {
+        let mut report = CheckReport::default();
+        let stat = self.stat()?;
+
+        let used_blocks: u64 = self
+            .block_usage_bitmap()?
+            .iter()
+            .map(|word| word.count_ones() as u64)
+            .sum();
+        let free_blocks = stat.blocks_count - used_blocks;
+        if free_blocks != stat.free_blocks_count {
+            report.errors.push(CheckError::FreeBlocksMismatch {
+                superblock: stat.free_blocks_count,
+                bitmap: free_blocks,
+            });
+        }
+
+        let inodes_per_group = u32::from_le(self.superblock().inodes_per_group);
+        let mut used_inodes = 0;
+        for group in 0..stat.inodes_count.div_ceil(inodes_per_group) {
+            let bitmap = self.read_inode_bitmap(group)?;
+            used_inodes += (0..inodes_per_group)
+                .filter(|&i| bitmap[i as usize / 8] & (1 << (i % 8)) != 0)
+                .count() as u32;
+        }
+        let free_inodes = stat.inodes_count - used_inodes;
+        if free_inodes != stat.free_inodes_count {
+            report.errors.push(CheckError::FreeInodesMismatch {
+                superblock: stat.free_inodes_count,
+                bitmap: free_inodes,
+            });
+        }
+
+        let inodes = self.iter_inodes().collect::<Ext4Result<Vec<_>>>()?;
+        for ino in inodes {
+            let (ty, nlink) =
+                self.with_inode_ref(ino, |inode| Ok((inode.inode_type(), inode.nlink())))?;
+            if nlink == 0 {
+                report.errors.push(CheckError::OrphanInode(ino));
+            }
+            if ty == InodeType::Directory {
+                let (mut dot, mut dotdot) = (false, false);
+                for entry in self.read_dir(ino, 0)? {
+                    let entry = entry?;
+                    dot |= entry.name == b".";
+                    dotdot |= entry.name == b"..";
+                }
+                if !dot {
+                    report.errors.push(CheckError::MissingDot(ino));
+                }
+                if !dotdot {
+                    report.errors.push(CheckError::MissingDotDot(ino));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
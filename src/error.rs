@@ -3,10 +3,31 @@ use core::{
     fmt::{Debug, Display},
 };
 
-use crate::ffi::EOK;
+use crate::ffi::*;
+
+/// Error codes not provided by lwext4's own errno list.
+pub(crate) const EINTR: u32 = 4;
+pub(crate) const EAGAIN: u32 = 11;
+pub(crate) const ENAMETOOLONG: u32 = 36;
+pub(crate) const ELOOP: u32 = 40;
 
 pub type Ext4Result<T = ()> = Result<T, Ext4Error>;
 
+/// Classification of [`Ext4Error::code`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    Exists,
+    NotEmpty,
+    NoSpace,
+    IsDirectory,
+    NotDirectory,
+    NameTooLong,
+    ReadOnly,
+    Io,
+    Other(i32),
+}
+
 pub struct Ext4Error {
     pub code: i32,
     pub context: Option<&'static str>,
@@ -18,6 +39,36 @@ impl Ext4Error {
             context: context.into(),
         }
     }
+
+    /// The raw errno value, as stored in [`Ext4Error::code`].
+    pub fn errno(&self) -> i32 {
+        self.code
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self.code as u32 {
+            ENOENT => ErrorKind::NotFound,
+            EEXIST => ErrorKind::Exists,
+            ENOTEMPTY => ErrorKind::NotEmpty,
+            ENOSPC => ErrorKind::NoSpace,
+            EISDIR => ErrorKind::IsDirectory,
+            ENOTDIR => ErrorKind::NotDirectory,
+            ENAMETOOLONG => ErrorKind::NameTooLong,
+            EROFS => ErrorKind::ReadOnly,
+            EIO => ErrorKind::Io,
+            _ => ErrorKind::Other(self.code),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.kind() == ErrorKind::NotFound
+    }
+    pub fn is_exists(&self) -> bool {
+        self.kind() == ErrorKind::Exists
+    }
+    pub fn is_not_empty(&self) -> bool {
+        self.kind() == ErrorKind::NotEmpty
+    }
 }
 
 impl From<i32> for Ext4Error {
@@ -26,6 +77,17 @@ impl From<i32> for Ext4Error {
     }
 }
 
+impl From<Ext4Error> for i32 {
+    fn from(err: Ext4Error) -> Self {
+        err.code
+    }
+}
+impl From<&Ext4Error> for i32 {
+    fn from(err: &Ext4Error) -> Self {
+        err.code
+    }
+}
+
 impl Display for Ext4Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         if let Some(context) = self.context {
@@ -0,0 +1,143 @@
+use crate::ffi::ext4_sblock;
+
+/// Filesystem feature flags, as recorded in the superblock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    pub compat: u32,
+    pub incompat: u32,
+    pub ro_compat: u32,
+}
+
+impl Features {
+    pub const COMPAT_DIR_PREALLOC: u32 = 0x0001;
+    pub const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+    pub const COMPAT_EXT_ATTR: u32 = 0x0008;
+    pub const COMPAT_RESIZE_INODE: u32 = 0x0010;
+    pub const COMPAT_DIR_INDEX: u32 = 0x0020;
+
+    pub const INCOMPAT_COMPRESSION: u32 = 0x0001;
+    pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+    pub const INCOMPAT_RECOVER: u32 = 0x0004;
+    pub const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+    pub const INCOMPAT_META_BG: u32 = 0x0010;
+    pub const INCOMPAT_EXTENTS: u32 = 0x0040;
+    pub const INCOMPAT_64BIT: u32 = 0x0080;
+    pub const INCOMPAT_MMP: u32 = 0x0100;
+    pub const INCOMPAT_FLEX_BG: u32 = 0x0200;
+    pub const INCOMPAT_EA_INODE: u32 = 0x0400;
+    pub const INCOMPAT_DIRDATA: u32 = 0x1000;
+    pub const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+    pub const INCOMPAT_LARGEDIR: u32 = 0x4000;
+    pub const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+    pub const INCOMPAT_ENCRYPT: u32 = 0x10000;
+    pub const INCOMPAT_CASEFOLD: u32 = 0x20000;
+
+    pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+    pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+    pub const RO_COMPAT_HUGE_FILE: u32 = 0x0008;
+    pub const RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+    pub const RO_COMPAT_DIR_NLINK: u32 = 0x0020;
+    pub const RO_COMPAT_EXTRA_ISIZE: u32 = 0x0040;
+    pub const RO_COMPAT_QUOTA: u32 = 0x0100;
+    pub const RO_COMPAT_BIGALLOC: u32 = 0x0200;
+    pub const RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+
+    pub(crate) fn from_sblock(sb: &ext4_sblock) -> Self {
+        Self {
+            compat: u32::from_le(sb.features_compatible),
+            incompat: u32::from_le(sb.features_incompatible),
+            ro_compat: u32::from_le(sb.features_read_only),
+        }
+    }
+
+    pub fn has_compat(&self, flags: u32) -> bool {
+        self.compat & flags == flags
+    }
+    pub fn has_incompat(&self, flags: u32) -> bool {
+        self.incompat & flags == flags
+    }
+    pub fn has_ro_compat(&self, flags: u32) -> bool {
+        self.ro_compat & flags == flags
+    }
+
+    pub fn has_journal(&self) -> bool {
+        self.has_compat(Self::COMPAT_HAS_JOURNAL)
+    }
+    pub fn has_dir_index(&self) -> bool {
+        self.has_compat(Self::COMPAT_DIR_INDEX)
+    }
+
+    pub fn has_filetype(&self) -> bool {
+        self.has_incompat(Self::INCOMPAT_FILETYPE)
+    }
+    pub fn has_extents(&self) -> bool {
+        self.has_incompat(Self::INCOMPAT_EXTENTS)
+    }
+    pub fn has_64bit(&self) -> bool {
+        self.has_incompat(Self::INCOMPAT_64BIT)
+    }
+    pub fn has_flex_bg(&self) -> bool {
+        self.has_incompat(Self::INCOMPAT_FLEX_BG)
+    }
+    pub fn has_inline_data(&self) -> bool {
+        self.has_incompat(Self::INCOMPAT_INLINE_DATA)
+    }
+
+    pub fn has_large_file(&self) -> bool {
+        self.has_ro_compat(Self::RO_COMPAT_LARGE_FILE)
+    }
+    pub fn has_huge_file(&self) -> bool {
+        self.has_ro_compat(Self::RO_COMPAT_HUGE_FILE)
+    }
+    pub fn has_bigalloc(&self) -> bool {
+        self.has_ro_compat(Self::RO_COMPAT_BIGALLOC)
+    }
+    pub fn has_metadata_csum(&self) -> bool {
+        self.has_ro_compat(Self::RO_COMPAT_METADATA_CSUM)
+    }
+    /// Whether group descriptors are checksummed, by either `gdt_csum` or
+    /// `metadata_csum`. Only then are the group `UNINIT` flags in effect.
+    pub fn has_group_desc_csum(&self) -> bool {
+        self.has_ro_compat(Self::RO_COMPAT_GDT_CSUM) || self.has_metadata_csum()
+    }
+}
+
+/// Default mount options recorded in the superblock (`s_default_mount_opts`),
+/// as set by `tune2fs -o`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DefaultMountOpts(pub u32);
+
+impl DefaultMountOpts {
+    pub const DEBUG: u32 = 0x0001;
+    pub const BSDGROUPS: u32 = 0x0002;
+    pub const XATTR_USER: u32 = 0x0004;
+    pub const ACL: u32 = 0x0008;
+    pub const UID16: u32 = 0x0010;
+    pub const JMODE: u32 = 0x0060;
+    pub const JMODE_DATA: u32 = 0x0020;
+    pub const JMODE_ORDERED: u32 = 0x0040;
+    pub const JMODE_WBACK: u32 = 0x0060;
+    pub const NOBARRIER: u32 = 0x0100;
+    pub const BLOCK_VALIDITY: u32 = 0x0200;
+    pub const DISCARD: u32 = 0x0400;
+    pub const NODELALLOC: u32 = 0x0800;
+
+    pub(crate) fn from_sblock(sb: &ext4_sblock) -> Self {
+        Self(u32::from_le(sb.default_mount_opts))
+    }
+
+    pub fn has(&self, flags: u32) -> bool {
+        self.0 & flags == flags
+    }
+
+    pub fn user_xattr(&self) -> bool {
+        self.has(Self::XATTR_USER)
+    }
+    pub fn acl(&self) -> bool {
+        self.has(Self::ACL)
+    }
+    /// Journaling data mode, one of the `JMODE_*` values, or `0` if unset.
+    pub fn journal_mode(&self) -> u32 {
+        self.0 & Self::JMODE
+    }
+}
@@ -1,17 +1,38 @@
-use core::{marker::PhantomData, mem, time::Duration};
+use core::{ffi::c_int, fmt::Write, marker::PhantomData, mem, ptr, slice, time::Duration};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
 
 use crate::{
-    DirLookupResult, DirReader, Ext4Error, Ext4Result, FileAttr, InodeRef, InodeType,
-    blockdev::{BlockDevice, Ext4BlockDevice},
+    DefaultMountOpts, DirLookupResult, DirReader, Ext4Error, Ext4Result, Features, FileAttr,
+    InodeRef, InodeType, TimeSpec,
+    blockdev::{BlockDevice, EXT4_DEV_BSIZE, Ext4BlockDevice},
     error::Context,
     ffi::*,
-    util::get_block_size,
+    inode::CacheCounters,
+    util::{
+        block_bitmap_block, desc_size, first_ino, get_block_size, inode_bitmap_block, inode_size,
+        inode_table_block, max_file_size,
+    },
 };
 
 pub trait SystemHal {
     fn now() -> Option<Duration>;
+
+    /// Called by lwext4 before each device access. Together with
+    /// [`SystemHal::unlock`] this lets the block layer run under a caller
+    /// provided lock.
+    fn lock() {}
+    /// Called by lwext4 after each device access.
+    fn unlock() {}
+}
+
+unsafe extern "C" fn hal_lock<Hal: SystemHal>(_bdev: *mut ext4_blockdev) -> c_int {
+    Hal::lock();
+    EOK as _
+}
+unsafe extern "C" fn hal_unlock<Hal: SystemHal>(_bdev: *mut ext4_blockdev) -> c_int {
+    Hal::unlock();
+    EOK as _
 }
 
 pub struct DummyHal;
@@ -21,8 +42,15 @@ impl SystemHal for DummyHal {
     }
 }
 
+/// Smallest block cache [`Ext4Filesystem::new`] accepts. lwext4 keeps a few
+/// metadata blocks referenced at once (inode table, extent path, bitmaps),
+/// and fails with `ENOMEM` once they no longer fit.
+pub const MIN_BCACHE_SIZE: u32 = 4;
+
 #[derive(Debug, Clone)]
 pub struct FsConfig {
+    /// Number of blocks kept in the block cache, at least
+    /// [`MIN_BCACHE_SIZE`].
     pub bcache_size: u32,
 }
 impl Default for FsConfig {
@@ -34,30 +62,116 @@ impl Default for FsConfig {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StatFs {
     pub inodes_count: u32,
     pub free_inodes_count: u32,
 
     pub blocks_count: u64,
     pub free_blocks_count: u64,
+    /// Blocks reserved for the superuser.
+    pub reserved_blocks_count: u64,
     pub block_size: u32,
 }
+impl StatFs {
+    /// Free blocks available to unprivileged users, as in `statvfs`'s
+    /// `f_bavail`.
+    pub fn available_blocks(&self) -> u64 {
+        self.free_blocks_count
+            .saturating_sub(self.reserved_blocks_count)
+    }
+}
+
+/// Device I/O counters, returned by [`Ext4Filesystem::io_stats`].
+///
+/// lwext4's block cache does not count its own lookups, so the cache counters
+/// only cover the file data lookups of [`Ext4Filesystem::read_at_nowait`] and
+/// [`Ext4Filesystem::prefetch`], one per block. Metadata lookups inside
+/// lwext4 are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Read requests issued to the device
+    pub reads: u32,
+    /// Write requests issued to the device
+    pub writes: u32,
+    /// Data blocks found in the block cache
+    pub cache_hits: u64,
+    /// Data blocks missing from the block cache
+    pub cache_misses: u64,
+}
+
+/// When modified metadata blocks in the block cache reach the device, see
+/// [`Ext4Filesystem::set_writeback_mode`]. File data is written to the device
+/// directly in either mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritebackMode {
+    /// Write each modified block back once the operation using it is done.
+    /// Every operation reaches the device before it returns.
+    Immediate,
+    /// Keep modified blocks cached until [`Ext4Filesystem::flush`], eviction
+    /// or unmount. Bursts of small updates share device writes, but a crash
+    /// loses all metadata changes since the last flush.
+    #[default]
+    Deferred,
+}
+
+/// Inode numbers of a directory created by [`Ext4Filesystem::mkdir_result`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MkdirResult {
+    pub ino: u32,
+    /// Target of the new directory's `.` entry.
+    pub dot_ino: u32,
+    /// Target of the new directory's `..` entry.
+    pub dotdot_ino: u32,
+}
 
 pub struct Ext4Filesystem<Hal: SystemHal, Dev: BlockDevice> {
     inner: Box<ext4_fs>,
     bdev: Ext4BlockDevice<Dev>,
+    // Geometry cached from the superblock, see `load_geometry`
+    block_size: u32,
+    blocks_count: u64,
+    blocks_per_group: u32,
+    inode_size: u16,
+    prealloc_blocks: u32,
+    next_generation: u32,
+    writeback_mode: WritebackMode,
+    cache_counters: CacheCounters,
     _phantom: PhantomData<Hal>,
 }
 
 impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
+    /// Mounts the filesystem on `dev`, read-write.
+    ///
+    /// Fails with `ENOTSUP` for `bigalloc` filesystems, which lwext4 cannot
+    /// allocate in, and for incompat features it does not support.
+    ///
+    /// The filesystem keeps the size it was formatted with. lwext4 cannot add
+    /// block groups, so on a larger `dev` the space past
+    /// [`Ext4Filesystem::blocks_count`] is left unused.
     pub fn new(dev: Dev, config: FsConfig) -> Ext4Result<Self> {
+        if config.bcache_size < MIN_BCACHE_SIZE {
+            return Err(Ext4Error::new(EINVAL as _, "block cache too small"));
+        }
         let mut bdev = Ext4BlockDevice::new(dev)?;
+        unsafe {
+            let bdif = &mut *bdev.inner.bdif;
+            bdif.lock = Some(hal_lock::<Hal>);
+            bdif.unlock = Some(hal_unlock::<Hal>);
+        }
         let mut fs = Box::new(unsafe { mem::zeroed() });
         unsafe {
             let bd = bdev.inner.as_mut();
+            check_superblock(bd)?;
             ext4_fs_init(&mut *fs, bd, false).context("ext4_fs_init")?;
 
             let bs = get_block_size(&fs.sb);
+            if bd.part_size < ext4_sb_get_blocks_cnt(&mut fs.sb) * bs as u64 {
+                return Err(Ext4Error::new(
+                    EINVAL as _,
+                    "device smaller than filesystem",
+                ));
+            }
             ext4_block_set_lb_size(bd, bs);
             ext4_bcache_init_dynamic(bd.bc, config.bcache_size, bs)
                 .context("ext4_bcache_init_dynamic")?;
@@ -70,17 +184,75 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
             let mut result = Self {
                 inner: fs,
                 bdev,
+                block_size: 0,
+                blocks_count: 0,
+                blocks_per_group: 0,
+                inode_size: 0,
+                prealloc_blocks: 0,
+                // Like the kernel, start from an arbitrary value so that
+                // generations differ across mounts
+                next_generation: Hal::now()
+                    .map_or(0, |now| now.subsec_nanos() ^ now.as_secs() as u32),
+                // `Ext4BlockDevice::new` enables write-back
+                writeback_mode: WritebackMode::Deferred,
+                cache_counters: CacheCounters::default(),
                 _phantom: PhantomData,
             };
+            result.load_geometry();
             let bd = result.bdev.inner.as_mut();
             ext4_block_bind_bcache(bd, bd.bc).context("ext4_block_bind_bcache")?;
             Ok(result)
         }
     }
 
+    /// Caches geometry read from the superblock. Must be called again
+    /// whenever the superblock fields it reads change.
+    fn load_geometry(&mut self) {
+        let sb = &self.inner.sb;
+        self.block_size = get_block_size(sb);
+        self.blocks_count = unsafe { ext4_sb_get_blocks_cnt(sb as *const _ as _) };
+        self.blocks_per_group = u32::from_le(sb.blocks_per_group);
+        self.inode_size = inode_size(sb);
+    }
+
+    /// Block size in bytes.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    /// Total number of blocks in the filesystem.
+    pub fn blocks_count(&self) -> u64 {
+        self.blocks_count
+    }
+    /// Number of blocks in each block group.
+    pub fn blocks_per_group(&self) -> u32 {
+        self.blocks_per_group
+    }
+    /// Allocation unit in bytes. Equal to [`Ext4Filesystem::block_size`]
+    /// unless the filesystem uses `bigalloc`.
+    pub fn cluster_size(&self) -> u32 {
+        if self.features().has_bigalloc() {
+            1024 << u32::from_le(self.inner.sb.log_cluster_size)
+        } else {
+            self.block_size
+        }
+    }
+    /// Size of an on-disk inode in bytes.
+    pub fn inode_size(&self) -> u16 {
+        self.inode_size
+    }
+
+    /// Makes [`Ext4Filesystem::write_at`] map at least `blocks` blocks up
+    /// front whenever a write extends a file, so that files grown by many
+    /// small appends stay in few extents. The blocks past EOF are released
+    /// when the file is shrunk or removed. `0`, the default, disables
+    /// preallocation.
+    pub fn set_prealloc_blocks(&mut self, blocks: u32) {
+        self.prealloc_blocks = blocks;
+    }
+
     fn inode_ref(&mut self, ino: u32) -> Ext4Result<InodeRef<Hal>> {
         unsafe {
-            let mut result = InodeRef::new(mem::zeroed());
+            let mut result = InodeRef::new(mem::zeroed(), self.block_size);
             ext4_fs_get_inode_ref(self.inner.as_mut(), ino, result.inner.as_mut())
                 .context("ext4_fs_get_inode_ref")?;
             Ok(result)
@@ -90,6 +262,142 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
         self.inode_ref(inode.ino()).expect("inode ref clone failed")
     }
 
+    pub(crate) fn superblock(&self) -> &ext4_sblock {
+        &self.inner.sb
+    }
+
+    /// Reads the inode bitmap of block group `bgid`, one bit per inode.
+    pub(crate) fn read_inode_bitmap(&mut self, bgid: u32) -> Ext4Result<Vec<u8>> {
+        let len = u32::from_le(self.inner.sb.inodes_per_group).div_ceil(8) as usize;
+        let bg = self.read_group_desc(bgid)?;
+        if self.group_has_flag(&bg, EXT4_BLOCK_GROUP_INODE_UNINIT) {
+            // No inode of the group is in use yet
+            return Ok(vec![0; len]);
+        }
+        self.read_bitmap_block(inode_bitmap_block(&self.inner.sb, &bg), len)
+    }
+    /// Reads the block bitmap of group `bgid`, one bit per cluster.
+    pub(crate) fn read_block_bitmap(&mut self, bgid: u32) -> Ext4Result<Vec<u8>> {
+        let clusters = self.blocks_per_group / (self.cluster_size() / self.block_size);
+        let len = clusters.div_ceil(8) as usize;
+        let bg = self.read_group_desc(bgid)?;
+        if self.group_has_flag(&bg, EXT4_BLOCK_GROUP_BLOCK_UNINIT) {
+            return Ok(self.uninit_block_bitmap(bgid, &bg, len));
+        }
+        self.read_bitmap_block(block_bitmap_block(&self.inner.sb, &bg), len)
+    }
+
+    /// Whether `flag`, one of the group `UNINIT` flags, is in effect for
+    /// `bg`. Like the kernel, the flags are ignored without descriptor
+    /// checksums.
+    fn group_has_flag(&self, bg: &ext4_bgroup, flag: u32) -> bool {
+        self.features().has_group_desc_csum() && u16::from_le(bg.flags) as u32 & flag != 0
+    }
+
+    /// Builds the block bitmap of a `BLOCK_UNINIT` group, in which only the
+    /// group's own metadata is in use, as `ext4_fs_init_block_bitmap` does.
+    /// Nothing is written to the device.
+    fn uninit_block_bitmap(&mut self, bgid: u32, bg: &ext4_bgroup, len: usize) -> Vec<u8> {
+        let block_size = self.block_size as u64;
+        let blocks_per_cluster = (self.cluster_size() / self.block_size) as u64;
+        let first = self.group_first_block(bgid);
+        let group_end = first + self.blocks_per_group as u64;
+        let inode_size = self.inode_size as u64;
+        let sb = &mut self.inner.sb;
+        let mut bitmap = vec![0u8; len];
+        let mut mark = |block: u64| {
+            if (first..group_end).contains(&block) {
+                let bit = ((block - first) / blocks_per_cluster) as usize;
+                bitmap[bit / 8] |= 1 << (bit % 8);
+            }
+        };
+
+        // Superblock backup and descriptor table
+        let per_block = block_size as u32 / desc_size(sb);
+        let first_meta_bg = u32::from_le(sb.first_meta_bg);
+        let mut base = unsafe { ext4_sb_is_super_in_bg(sb, bgid) } as u64;
+        if !Features::from_sblock(sb).has_incompat(Features::INCOMPAT_META_BG)
+            || bgid < first_meta_bg * per_block
+        {
+            if base > 0 {
+                base += unsafe { ext4_bg_num_gdb(sb, bgid) } as u64;
+                base += u16::from_le(sb.s_reserved_gdt_blocks) as u64;
+            }
+        } else {
+            base += unsafe { ext4_bg_num_gdb(sb, bgid) } as u64;
+        }
+        (first..first + base).for_each(&mut mark);
+
+        // With `flex_bg` these usually live in another group
+        mark(block_bitmap_block(sb, bg));
+        mark(inode_bitmap_block(sb, bg));
+        let table = inode_table_block(sb, bg);
+        let table_len =
+            (u32::from_le(sb.inodes_per_group) as u64 * inode_size).div_ceil(block_size);
+        (table..table + table_len).for_each(mark);
+        bitmap
+    }
+
+    /// Reads the descriptor of group `bgid` from the descriptor table.
+    /// Unlike `ext4_fs_get_block_group_ref`, this leaves the bitmaps of an
+    /// uninit group as they are rather than initializing them on disk, so
+    /// that scans don't write.
+    fn read_group_desc(&mut self, bgid: u32) -> Ext4Result<ext4_bgroup> {
+        let sb = &mut self.inner.sb;
+        let desc_size = desc_size(sb);
+        let per_block = self.block_size / desc_size;
+        let desc_block = bgid / per_block;
+        let lba = if !Features::from_sblock(sb).has_incompat(Features::INCOMPAT_META_BG)
+            || desc_block < u32::from_le(sb.first_meta_bg)
+        {
+            u32::from_le(sb.first_data_block) as u64 + desc_block as u64 + 1
+        } else {
+            // The first group of each meta group holds its descriptor block,
+            // after the superblock backup if there is one
+            let group = desc_block * per_block;
+            let has_super = unsafe { ext4_sb_is_super_in_bg(sb, group) };
+            self.group_first_block(group) + has_super as u64
+        };
+
+        let offset = (bgid % per_block * desc_size) as usize;
+        let len = (desc_size as usize).min(size_of::<ext4_bgroup>());
+        unsafe {
+            let bdev = self.bdev.inner.as_mut();
+            let mut block = mem::zeroed();
+            ext4_block_get(bdev, &mut block, lba).context("ext4_block_get")?;
+            let mut bg: ext4_bgroup = mem::zeroed();
+            slice::from_raw_parts_mut(&mut bg as *mut _ as *mut u8, len)
+                .copy_from_slice(slice::from_raw_parts(block.data.add(offset), len));
+            ext4_block_set(bdev, &mut block).context("ext4_block_set")?;
+            Ok(bg)
+        }
+    }
+
+    fn read_bitmap_block(&mut self, lba: u64, len: usize) -> Ext4Result<Vec<u8>> {
+        unsafe {
+            let bdev = self.bdev.inner.as_mut();
+            let mut block = mem::zeroed();
+            ext4_block_get(bdev, &mut block, lba).context("ext4_block_get")?;
+            let bitmap = slice::from_raw_parts(block.data, len).to_vec();
+            ext4_block_set(bdev, &mut block).context("ext4_block_set")?;
+            Ok(bitmap)
+        }
+    }
+
+    /// First block of group `bgid`.
+    fn group_first_block(&self, bgid: u32) -> u64 {
+        u32::from_le(self.inner.sb.first_data_block) as u64
+            + bgid as u64 * self.blocks_per_group as u64
+    }
+
+    /// Runs `f` on a reference to inode `ino`.
+    ///
+    /// The filesystem stays mutably borrowed while `f` runs, so `f` cannot
+    /// reach it to open a second reference to the same inode; the borrow
+    /// checker rules out re-entrant access without a runtime guard. Where the
+    /// crate itself holds two references to one inode, both point into the
+    /// same cached inode table block, as lwext4 hands out one shared buffer
+    /// per block, so they never diverge.
     pub fn with_inode_ref<R>(
         &mut self,
         ino: u32,
@@ -99,6 +407,13 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
         f(&mut inode)
     }
 
+    /// Allocates an inode of type `ty`.
+    ///
+    /// lwext4 resumes the search at the group of the previous allocation
+    /// (`last_inode_bg_id`), so each call reads a single bitmap block unless
+    /// that group is full; the bit search within the block is done in memory.
+    /// [`Ext4Filesystem::create`] points that hint at the parent's group
+    /// first, see [`Ext4Filesystem::hint_inode_group`].
     pub(crate) fn alloc_inode(&mut self, ty: InodeType) -> Ext4Result<InodeRef<Hal>> {
         unsafe {
             let ty = match ty {
@@ -111,14 +426,40 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
                 InodeType::Socket => EXT4_DE_SOCK,
                 InodeType::Unknown => EXT4_DE_UNKNOWN,
             };
-            let mut result = InodeRef::new(mem::zeroed());
+            let mut result = InodeRef::new(mem::zeroed(), self.block_size);
             ext4_fs_alloc_inode(self.inner.as_mut(), result.inner.as_mut(), ty as _)
                 .context("ext4_fs_get_inode_ref")?;
             ext4_fs_inode_blocks_init(self.inner.as_mut(), result.inner.as_mut());
+            result.set_generation(self.next_generation);
+            self.next_generation = self.next_generation.wrapping_add(1);
+            if let Some(now) = Hal::now() {
+                result.set_atime(&now);
+                result.set_mtime(&now);
+                result.set_ctime(&now);
+                result.set_crtime(&now);
+            }
             Ok(result)
         }
     }
 
+    /// Starts the next inode search in the block group of `parent`, so that
+    /// files end up close to their directory. lwext4 has no parent argument
+    /// and would otherwise continue from its previous allocation. New
+    /// directories keep that rotating start, which spreads them over groups.
+    ///
+    /// lwext4 also starts the search for a file's first data block in the
+    /// group of its inode, so this keeps the data of small files in one
+    /// directory together as well.
+    fn hint_inode_group(&mut self, parent: u32) {
+        let inodes_per_group = u32::from_le(self.inner.sb.inodes_per_group);
+        self.inner.last_inode_bg_id = parent.saturating_sub(1) / inodes_per_group;
+    }
+
+    pub fn set_times(&mut self, ino: u32, atime: TimeSpec, mtime: TimeSpec) -> Ext4Result {
+        self.inode_ref(ino)?.set_times(atime, mtime);
+        Ok(())
+    }
+
     pub fn get_attr(&mut self, ino: u32, attr: &mut FileAttr) -> Ext4Result<()> {
         self.inode_ref(ino)?.get_attr(attr);
         Ok(())
@@ -127,36 +468,377 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
     pub fn read_at(&mut self, ino: u32, buf: &mut [u8], offset: u64) -> Ext4Result<usize> {
         self.inode_ref(ino)?.read_at(buf, offset)
     }
+    /// Reads the whole file. Holes read back as zeros.
+    pub fn read_all(&mut self, ino: u32) -> Ext4Result<Vec<u8>> {
+        let mut inode = self.inode_ref(ino)?;
+        let mut buf = vec![0; inode.size() as usize];
+        let mut pos = 0;
+        for chunk in buf.chunks_mut(self.block_size as usize) {
+            let read = inode.read_at(chunk, pos as u64)?;
+            pos += read;
+            if read < chunk.len() {
+                break;
+            }
+        }
+        buf.truncate(pos);
+        Ok(buf)
+    }
+    pub fn read_at_cancellable(
+        &mut self,
+        ino: u32,
+        buf: &mut [u8],
+        offset: u64,
+        cancel: &dyn Fn() -> bool,
+    ) -> Ext4Result<usize> {
+        self.inode_ref(ino)?
+            .read_at_cancellable(buf, offset, cancel)
+    }
+    pub fn read_at_vectored(
+        &mut self,
+        ino: u32,
+        bufs: &mut [&mut [u8]],
+        offset: u64,
+    ) -> Ext4Result<usize> {
+        self.inode_ref(ino)?.read_at_vectored(bufs, offset)
+    }
+    pub fn read_at_nowait(&mut self, ino: u32, buf: &mut [u8], offset: u64) -> Ext4Result<usize> {
+        self.inode_ref(ino)?
+            .read_at_nowait_counted(buf, offset, &mut self.cache_counters)
+    }
+    pub fn prefetch(&mut self, ino: u32, offset: u64, len: u64) -> Ext4Result {
+        self.inode_ref(ino)?
+            .prefetch_counted(offset, len, &mut self.cache_counters)
+    }
+    /// See [`InodeRef::contiguous_len`].
+    pub fn contiguous_len(&mut self, ino: u32, pos: u64) -> Ext4Result<u64> {
+        self.inode_ref(ino)?.contiguous_len(pos)
+    }
     pub fn write_at(&mut self, ino: u32, buf: &[u8], offset: u64) -> Ext4Result<usize> {
-        self.inode_ref(ino)?.write_at(buf, offset)
+        let prealloc = self.prealloc_blocks;
+        self.inode_ref(ino)?
+            .write_at_prealloc(buf, offset, prealloc)
+    }
+    pub fn write_at_vectored(
+        &mut self,
+        ino: u32,
+        bufs: &[&[u8]],
+        offset: u64,
+    ) -> Ext4Result<usize> {
+        self.inode_ref(ino)?.write_at_vectored(bufs, offset)
+    }
+    /// Like [`Ext4Filesystem::write_at`], but for an unprivileged caller also
+    /// clears the setuid/setgid bits, see [`InodeRef::kill_setid`].
+    pub fn write_at_as(
+        &mut self,
+        ino: u32,
+        buf: &[u8],
+        offset: u64,
+        privileged: bool,
+    ) -> Ext4Result<usize> {
+        let mut inode = self.inode_ref(ino)?;
+        let written = inode.write_at_prealloc(buf, offset, self.prealloc_blocks)?;
+        if !privileged && written > 0 {
+            inode.kill_setid();
+        }
+        Ok(written)
+    }
+    /// Replaces the contents of `ino` with `data`. A larger file is shrunk
+    /// first, freeing the blocks past the new end.
+    pub fn write_all(&mut self, ino: u32, data: &[u8]) -> Ext4Result {
+        let prealloc = self.prealloc_blocks;
+        let mut inode = self.inode_ref(ino)?;
+        if inode.is_append_only() {
+            return Err(Ext4Error::new(EPERM as _, "inode is append-only"));
+        }
+        if inode.size() > data.len() as u64 {
+            inode.set_len(data.len() as u64)?;
+        }
+        inode.write_at_prealloc(data, 0, prealloc)?;
+        inode.update_mtime();
+        inode.update_ctime();
+        Ok(())
+    }
+    /// Like [`Ext4Filesystem::write_at`], but flushes the block cache before
+    /// returning so that the data and the inode update are on the device, as
+    /// with `O_SYNC`.
+    pub fn write_at_sync(&mut self, ino: u32, buf: &[u8], offset: u64) -> Ext4Result<usize> {
+        let written = self.write_at(ino, buf, offset)?;
+        self.flush()?;
+        Ok(written)
+    }
+    /// Copies up to `len` bytes from `src_ino` at `src_off` to `dst_ino` at
+    /// `dst_off` without going through a caller buffer, extending the
+    /// destination as needed. Holes in the source read back as zeros in the
+    /// destination: they are written as zeros over existing data and left
+    /// as holes past its old end. Returns the number of bytes copied, which
+    /// is short only at the end of the source.
+    pub fn copy_file_range(
+        &mut self,
+        src_ino: u32,
+        src_off: u64,
+        dst_ino: u32,
+        dst_off: u64,
+        len: u64,
+    ) -> Ext4Result<usize> {
+        let mut src = self.inode_ref(src_ino)?;
+        let len = len.min(src.size().saturating_sub(src_off));
+        let src_end = src_off + len;
+        let dst_end = dst_off
+            .checked_add(len)
+            .ok_or(Ext4Error::new(EINVAL as _, "offset overflow"))?;
+        if src_ino == dst_ino && src_off < dst_end && dst_off < src_end {
+            return Err(Ext4Error::new(EINVAL as _, "overlapping copy ranges"));
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let block_size = self.block_size as u64;
+        // Source ranges, and whether each is a hole
+        let ranges: Vec<(u64, u64, bool)> = if src.has_inline_data() {
+            vec![(src_off, src_end, false)]
+        } else {
+            src.fiemap(src_off, len)?
+                .into_iter()
+                .map(|extent| {
+                    let start = extent.logical * block_size;
+                    let end = start + extent.length * block_size;
+                    (start.max(src_off), end.min(src_end), extent.is_hole)
+                })
+                .collect()
+        };
+
+        let mut dst = self.inode_ref(dst_ino)?;
+        // Source offset matching the old end of the destination
+        let dst_size_in_src = src_off.saturating_add(dst.size().saturating_sub(dst_off));
+        let mut buf = vec![0; block_size as usize];
+        for (mut pos, end, is_hole) in ranges {
+            let end = if is_hole {
+                end.min(dst_size_in_src)
+            } else {
+                end
+            };
+            while pos < end {
+                let chunk = ((end - pos) as usize).min(buf.len());
+                let read = if is_hole {
+                    buf[..chunk].fill(0);
+                    chunk
+                } else {
+                    src.read_at(&mut buf[..chunk], pos)?
+                };
+                if read == 0 {
+                    break;
+                }
+                dst.write_at(&buf[..read], dst_off + (pos - src_off))?;
+                pos += read as u64;
+            }
+        }
+        // Holes past the old end are not visited above
+        if dst.size() < dst_end {
+            dst.set_len(dst_end)?;
+        }
+        dst.update_mtime();
+        dst.update_ctime();
+        Ok(len as usize)
     }
     pub fn set_len(&mut self, ino: u32, len: u64) -> Ext4Result<()> {
         self.inode_ref(ino)?.set_len(len)
     }
+    /// Like [`Ext4Filesystem::set_len`], but for an unprivileged caller also
+    /// clears the setuid/setgid bits, see [`InodeRef::kill_setid`].
+    pub fn set_len_as(&mut self, ino: u32, len: u64, privileged: bool) -> Ext4Result<()> {
+        let mut inode = self.inode_ref(ino)?;
+        inode.set_len(len)?;
+        if !privileged {
+            inode.kill_setid();
+        }
+        Ok(())
+    }
     pub fn set_symlink(&mut self, ino: u32, buf: &[u8]) -> Ext4Result<()> {
         self.inode_ref(ino)?.set_symlink(buf)
     }
-    pub fn lookup(&mut self, parent: u32, name: &str) -> Ext4Result<DirLookupResult<Hal>> {
+    pub fn read_link(&mut self, ino: u32) -> Ext4Result<Vec<u8>> {
+        let mut inode = self.inode_ref(ino)?;
+        if inode.inode_type() != InodeType::Symlink {
+            return Err(Ext4Error::new(EINVAL as _, "not a symlink"));
+        }
+        let mut buf = vec![0; inode.size() as usize];
+        let len = inode.read_at(&mut buf, 0)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+    /// Looks up `name` in `parent`. See [`InodeRef::lookup`] for when a
+    /// missing entry is reported.
+    pub fn lookup(&mut self, parent: u32, name: &str) -> Ext4Result<DirLookupResult<'_, Hal>> {
         self.inode_ref(parent)?.lookup(name)
     }
-    pub fn read_dir(&mut self, parent: u32, offset: u64) -> Ext4Result<DirReader<Hal>> {
+    /// Looks up `name` in `parent` and fills `attr` for the entry found.
+    pub fn lookup_attr(&mut self, parent: u32, name: &str, attr: &mut FileAttr) -> Ext4Result {
+        let mut result = self.inode_ref(parent)?.lookup(name)?;
+        // Taken while the entry is still held, so the attributes are those of
+        // the inode it names
+        let inode = self.inode_ref(result.entry().ino())?;
+        inode.get_attr(attr);
+        drop(result);
+        Ok(())
+    }
+    pub fn read_dir(&mut self, parent: u32, offset: u64) -> Ext4Result<DirReader<'_, Hal>> {
         self.inode_ref(parent)?.read_dir(offset)
     }
 
     pub fn create(&mut self, parent: u32, name: &str, ty: InodeType, mode: u32) -> Ext4Result<u32> {
-        let mut child = self.alloc_inode(ty)?;
+        Ok(self.create_inode(parent, name, ty, mode)?.ino())
+    }
+    /// Like [`Ext4Filesystem::create`], but fails with `EEXIST` if `name`
+    /// already exists in `parent`, as with `O_CREAT | O_EXCL`.
+    ///
+    /// The lookup and the insertion happen under the same exclusive borrow
+    /// and the same reference to `parent`, so no other operation can add
+    /// `name` in between; across threads, `SyncExt4` gives the same
+    /// guarantee.
+    pub fn create_exclusive(
+        &mut self,
+        parent: u32,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+    ) -> Ext4Result<u32> {
+        let mut dir = self.inode_ref(parent)?;
+        Ok(self.create_exclusive_in(&mut dir, name, ty, mode)?.ino())
+    }
+    fn create_exclusive_in(
+        &mut self,
+        dir: &mut InodeRef<Hal>,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+    ) -> Ext4Result<InodeRef<Hal>> {
+        match self.clone_ref(dir).lookup(name) {
+            Ok(_) => return Err(Ext4Error::new(EEXIST as _, None)),
+            Err(err) if err.is_not_found() => {}
+            Err(err) => return Err(err),
+        }
+        self.create_inode_in(dir, name, ty, mode)
+    }
+    /// Like [`Ext4Filesystem::create`], also setting the owner of the new
+    /// inode.
+    pub fn create_with_owner(
+        &mut self,
+        parent: u32,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Ext4Result<u32> {
+        let mut child = self.create_inode(parent, name, ty, mode)?;
+        child.set_owner(uid, gid);
+        Ok(child.ino())
+    }
+
+    fn create_inode(
+        &mut self,
+        parent: u32,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+    ) -> Ext4Result<InodeRef<Hal>> {
         let mut parent = self.inode_ref(parent)?;
-        parent.add_entry(name, &mut child)?;
+        self.create_inode_in(&mut parent, name, ty, mode)
+    }
+    fn create_inode_in(
+        &mut self,
+        parent: &mut InodeRef<Hal>,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+    ) -> Ext4Result<InodeRef<Hal>> {
+        if is_dot_or_dotdot(name) {
+            return Err(Ext4Error::new(EEXIST as _, None));
+        }
+        // Type bits in `mode` are optional, but must agree with `ty`
+        let mode_ty = (mode >> 12) & 0xf;
+        if mode_ty != 0 && InodeType::from(mode_ty as u8) != ty {
+            return Err(Ext4Error::new(
+                EINVAL as _,
+                "mode does not match inode type",
+            ));
+        }
+        if ty != InodeType::Directory {
+            self.hint_inode_group(parent.ino());
+        }
+        let mut child = self.alloc_inode(ty)?;
+        // Inline data past `i_block` needs in-inode attribute space, which
+        // 128-byte inodes lack; lwext4 would put it in an external block
+        if ty == InodeType::RegularFile
+            && self.features().has_inline_data()
+            && self.inode_size as u32 > EXT4_GOOD_OLD_INODE_SIZE
+            && let Err(err) = child.init_inline()
+        {
+            self.free_inode(&mut child)?;
+            return Err(err);
+        }
+        let parent_size = parent.size();
+        if let Err(err) = parent.add_entry(name, &mut child) {
+            // lwext4 grows the directory before setting up the new block, so
+            // a later failure can leave an uninitialized block at the end
+            if parent.size() > parent_size {
+                parent.truncate(parent_size)?;
+            }
+            self.free_inode(&mut child)?;
+            return Err(err);
+        }
         if ty == InodeType::Directory {
-            child.add_entry(".", &mut self.clone_ref(&child))?;
-            child.add_entry("..", &mut parent)?;
+            let dots = child
+                .add_entry(".", &mut self.clone_ref(&child))
+                .and_then(|_| child.add_entry("..", parent));
+            if let Err(err) = dots {
+                parent.remove_entry(name.as_bytes(), &mut child)?;
+                self.free_inode(&mut child)?;
+                return Err(err);
+            }
             assert_eq!(child.nlink(), 2);
         }
         child.set_mode((child.mode() & !0o777) | (mode & 0o777));
 
-        Ok(child.ino())
+        Ok(child)
     }
 
+    /// Creates a special file. For character and block devices, `rdev` is the
+    /// device ID in the Linux `dev_t` encoding; it is ignored otherwise.
+    pub fn mknod(
+        &mut self,
+        parent: u32,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+        rdev: u32,
+    ) -> Ext4Result<u32> {
+        let ino = self.create(parent, name, ty, mode)?;
+        let mut inode = self.inode_ref(ino)?;
+        if inode.is_device() {
+            inode.set_rdev(rdev);
+        }
+        Ok(ino)
+    }
+
+    /// Creates a directory, also returning the targets of its `.` and `..`
+    /// entries.
+    pub fn mkdir_result(&mut self, parent: u32, name: &str, mode: u32) -> Ext4Result<MkdirResult> {
+        let ino = self.create(parent, name, InodeType::Directory, mode)?;
+        Ok(MkdirResult {
+            ino,
+            dot_ino: ino,
+            dotdot_ino: parent,
+        })
+    }
+
+    /// Moves `src_name` in `src_dir` to `dst_name` in `dst_dir`, replacing
+    /// any existing destination.
+    ///
+    /// The inode is relinked rather than copied: apart from its link count
+    /// (and the `..` entry of a directory), its contents, extended attributes
+    /// and flags are left untouched.
     pub fn rename(
         &mut self,
         src_dir: u32,
@@ -167,29 +849,58 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
         let mut src_dir_ref = self.inode_ref(src_dir)?;
         let mut dst_dir_ref = self.inode_ref(dst_dir)?;
 
-        // TODO: optimize
-        match self.unlink(dst_dir, dst_name) {
-            Ok(_) => {}
-            Err(err) if err.code == ENOENT as i32 => {}
-            Err(err) => return Err(err),
+        if is_dot_or_dotdot(src_name) || is_dot_or_dotdot(dst_name) {
+            return Err(Ext4Error::new(EINVAL as _, "cannot rename . or .."));
+        }
+        if src_dir == dst_dir && src_name == dst_name {
+            // Still report a missing source
+            self.lookup(src_dir, src_name)?;
+            return Ok(());
         }
 
+        // Check everything that can fail before the source entry is removed,
+        // so that a failure can't leave the inode unlinked. Moving a
+        // directory also adds a link to its new parent.
         let src = self.lookup(src_dir, src_name)?.entry().ino();
-
         let mut src_ref = self.inode_ref(src)?;
+        src_dir_ref.check_remove_entry(&src_ref)?;
+        if src_dir != dst_dir && src_ref.is_dir() {
+            dst_dir_ref.check_nlink_limit()?;
+        }
+        let replacing = match self.lookup(dst_dir, dst_name) {
+            Ok(_) => true,
+            Err(err) if err.is_not_found() => false,
+            Err(err) => return Err(err),
+        };
+        dst_dir_ref.check_add_entry(dst_name)?;
+
+        // TODO: optimize
+        if replacing {
+            self.unlink(dst_dir, dst_name)?;
+        }
+
+        src_dir_ref.remove_entry(src_name.as_bytes(), &mut src_ref)?;
+        if let Err(err) = dst_dir_ref.add_entry(dst_name, &mut src_ref) {
+            // Failures the checks above can't rule out, such as I/O errors.
+            // The slot just freed in the source directory takes the entry
+            // back without allocating.
+            src_dir_ref.add_entry(src_name, &mut src_ref)?;
+            return Err(err);
+        }
         if src_ref.is_dir() {
             let mut result = self.clone_ref(&src_ref).lookup("..")?;
             result.entry().raw_entry_mut().set_ino(dst_dir);
             src_dir_ref.dec_nlink();
             dst_dir_ref.inc_nlink();
         }
-        src_dir_ref.remove_entry(src_name, &mut src_ref)?;
-        dst_dir_ref.add_entry(dst_name, &mut src_ref)?;
 
         Ok(())
     }
 
     pub fn link(&mut self, dir: u32, name: &str, child: u32) -> Ext4Result {
+        if is_dot_or_dotdot(name) {
+            return Err(Ext4Error::new(EEXIST as _, None));
+        }
         let mut child_ref = self.inode_ref(child)?;
         if child_ref.is_dir() {
             return Err(Ext4Error::new(EISDIR as _, "cannot link to directory"));
@@ -199,17 +910,27 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
     }
 
     pub fn unlink(&mut self, dir: u32, name: &str) -> Ext4Result {
+        self.unlink_bytes(dir, name.as_bytes())
+    }
+
+    /// Like [`Ext4Filesystem::unlink`], for names that need not be UTF-8.
+    pub(crate) fn unlink_bytes(&mut self, dir: u32, name: &[u8]) -> Ext4Result {
+        if matches!(name, b"." | b"..") {
+            return Err(Ext4Error::new(EINVAL as _, "cannot remove . or .."));
+        }
         let mut dir_ref = self.inode_ref(dir)?;
-        let child = self.clone_ref(&dir_ref).lookup(name)?.entry().ino();
+        let child = self.clone_ref(&dir_ref).lookup_bytes(name)?.entry().ino();
         let mut child_ref = self.inode_ref(child)?;
 
-        if self.clone_ref(&child_ref).has_children()? {
-            return Err(Ext4Error::new(ENOTEMPTY as _, None));
-        }
-        if child_ref.inode_type() == InodeType::Directory {
+        if child_ref.is_dir() {
+            // Stops at the first live entry. Later blocks may still hold
+            // entries after earlier ones were emptied, so checking only the
+            // first block would not be enough.
+            if !self.clone_ref(&child_ref).is_empty_dir()? {
+                return Err(Ext4Error::new(ENOTEMPTY as _, None));
+            }
             // According to `ext4_trunc_dir`
-            let bs = get_block_size(&self.inner.as_mut().sb);
-            child_ref.truncate(bs as _)?;
+            child_ref.truncate(self.block_size as _)?;
         }
 
         dir_ref.remove_entry(name, &mut child_ref)?;
@@ -219,18 +940,57 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
             child_ref.dec_nlink();
         }
         if child_ref.nlink() == 0 {
-            child_ref.truncate(0)?;
-            unsafe {
-                ext4_inode_set_del_time(child_ref.inner.inode, u32::MAX);
-                child_ref.mark_dirty();
-                ext4_fs_free_inode(child_ref.inner.as_mut());
+            self.free_inode(&mut child_ref)?;
+        }
+        Ok(())
+    }
+
+    /// Releases the blocks of an inode with no links left, then the inode
+    /// itself.
+    fn free_inode(&mut self, inode: &mut InodeRef<Hal>) -> Ext4Result {
+        inode.truncate(0)?;
+        self.remove_orphan(inode)?;
+        // Any non-zero value marks the inode as deleted
+        let dtime = Hal::now().map_or(u32::MAX, |now| now.as_secs() as u32);
+        unsafe {
+            ext4_inode_set_del_time(inode.inner.inode, dtime);
+            inode.mark_dirty();
+            ext4_fs_free_inode(inode.inner.as_mut());
+        }
+        Ok(())
+    }
+
+    /// Removes `inode` from the superblock's orphan list, if present, so that
+    /// orphan processing never touches it after it is freed.
+    fn remove_orphan(&mut self, inode: &mut InodeRef<Hal>) -> Ext4Result {
+        let ino = inode.ino();
+        // Orphan inodes chain through their deletion time field
+        let next = inode.raw_inode().deletion_time;
+
+        let mut cur = u32::from_le(self.inner.sb.last_orphan);
+        if cur == ino {
+            self.inner.sb.last_orphan = next;
+            return self.write_superblock();
+        }
+        let mut remaining = u32::from_le(self.inner.sb.inodes_count);
+        while cur != 0 && remaining > 0 {
+            let mut cur_ref = self.inode_ref(cur)?;
+            let cur_next = u32::from_le(cur_ref.raw_inode().deletion_time);
+            if cur_next == ino {
+                cur_ref.raw_inode_mut().deletion_time = next;
+                cur_ref.mark_dirty();
+                break;
             }
+            cur = cur_next;
+            remaining -= 1;
         }
         Ok(())
     }
 
+    /// Returns filesystem usage. The superblock keeps its counts in blocks
+    /// even on `bigalloc` filesystems, so no cluster conversion is needed.
     pub fn stat(&mut self) -> Ext4Result<StatFs> {
-        let sb = &mut self.inner.as_mut().sb;
+        let sb = &self.inner.sb;
         Ok(StatFs {
             inodes_count: u32::from_le(sb.inodes_count),
             free_inodes_count: u32::from_le(sb.free_inodes_count),
@@ -238,16 +998,277 @@ impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
                 | u32::from_le(sb.blocks_count_lo) as u64,
             free_blocks_count: (u32::from_le(sb.free_blocks_count_hi) as u64) << 32
                 | u32::from_le(sb.free_blocks_count_lo) as u64,
-            block_size: get_block_size(sb),
+            reserved_blocks_count: (u32::from_le(sb.reserved_blocks_count_hi) as u64) << 32
+                | u32::from_le(sb.reserved_blocks_count_lo) as u64,
+            block_size: self.block_size,
         })
     }
 
+    pub fn io_stats(&self) -> IoStats {
+        let bdif = unsafe { &*self.bdev.inner.bdif };
+        IoStats {
+            reads: bdif.bread_ctr,
+            writes: bdif.bwrite_ctr,
+            cache_hits: self.cache_counters.hits,
+            cache_misses: self.cache_counters.misses,
+        }
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.inner.sb.uuid
+    }
+    /// Formats [`Ext4Filesystem::uuid`] as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub fn uuid_string(&self) -> String {
+        let mut result = String::with_capacity(36);
+        for (i, byte) in self.uuid().iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                result.push('-');
+            }
+            let _ = write!(result, "{byte:02x}");
+        }
+        result
+    }
+
+    pub fn volume_label(&self) -> Ext4Result<String> {
+        let name = self.inner.sb.volume_name.map(|c| c as u8);
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        Ok(String::from_utf8_lossy(&name[..len]).into_owned())
+    }
+    pub fn set_volume_label(&mut self, label: &str) -> Ext4Result {
+        let sb = &mut self.inner.sb;
+        if label.len() > sb.volume_name.len() {
+            return Err(Ext4Error::new(EINVAL as _, "volume label too long"));
+        }
+        sb.volume_name.fill(0);
+        for (dst, src) in sb.volume_name.iter_mut().zip(label.bytes()) {
+            *dst = src as _;
+        }
+        self.write_superblock()
+    }
+
+    fn write_superblock(&mut self) -> Ext4Result {
+        unsafe {
+            ext4_sb_write(self.bdev.inner.as_mut(), &mut self.inner.sb).context("ext4_sb_write")
+        }
+    }
+
+    pub fn features(&self) -> Features {
+        Features::from_sblock(&self.inner.sb)
+    }
+    /// Default mount options from the superblock. lwext4 applies none of
+    /// them, so they are left for callers to honor.
+    pub fn default_mount_opts(&self) -> DefaultMountOpts {
+        DefaultMountOpts::from_sblock(&self.inner.sb)
+    }
+
+    /// First inode number available to regular files; inodes below it are
+    /// reserved for special purposes (root directory, journal, etc.).
+    pub fn first_ino(&self) -> u32 {
+        first_ino(&self.inner.sb)
+    }
+    pub fn is_reserved_ino(&self, ino: u32) -> bool {
+        ino < self.first_ino()
+    }
+
+    /// Largest file size supported by this filesystem, in bytes.
+    pub fn max_file_size(&self) -> u64 {
+        max_file_size(&self.inner.sb)
+    }
+
+    /// Sets or clears the immutable flag, like `chattr +i`/`-i`.
+    pub fn set_immutable(&mut self, ino: u32, immutable: bool) -> Ext4Result {
+        self.set_inode_flag(ino, EXT4_INODE_FLAG_IMMUTABLE, immutable)
+    }
+    /// Sets or clears the append-only flag, like `chattr +a`/`-a`.
+    pub fn set_append_only(&mut self, ino: u32, append_only: bool) -> Ext4Result {
+        self.set_inode_flag(ino, EXT4_INODE_FLAG_APPEND, append_only)
+    }
+    fn set_inode_flag(&mut self, ino: u32, flag: u32, on: bool) -> Ext4Result {
+        let mut inode = self.inode_ref(ino)?;
+        let flags = inode.flags();
+        inode.set_flags(if on { flags | flag } else { flags & !flag });
+        inode.update_ctime();
+        Ok(())
+    }
+
+    /// Whether the block cache holds modified blocks that
+    /// [`Ext4Filesystem::flush`] would write out. File data is written to the
+    /// device directly and never makes the cache dirty.
+    pub fn is_dirty(&self) -> bool {
+        unsafe { !(*self.bdev.inner.bc).dirty_list.slh_first.is_null() }
+    }
+
+    pub fn writeback_mode(&self) -> WritebackMode {
+        self.writeback_mode
+    }
+    /// Switches the write-back policy of the block cache. Switching to
+    /// [`WritebackMode::Immediate`] writes out everything already dirty.
+    pub fn set_writeback_mode(&mut self, mode: WritebackMode) -> Ext4Result {
+        if mode == self.writeback_mode {
+            return Ok(());
+        }
+        // lwext4 counts write-back requests and only writes through once
+        // none are left; `WritebackGuard` nests on top of this one
+        let on = mode == WritebackMode::Deferred;
+        unsafe {
+            ext4_block_cache_write_back(self.bdev.inner.as_mut(), on as u8)
+                .context("ext4_block_cache_write_back")?;
+        }
+        self.writeback_mode = mode;
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Ext4Result<()> {
         unsafe {
             ext4_block_cache_flush(self.bdev.inner.as_mut()).context("ext4_cache_flush")?;
         }
         Ok(())
     }
+
+    /// Copies the filesystem to `dst`, which must be at least as large.
+    ///
+    /// Pending writes are flushed first, and the copy of the superblock on
+    /// `dst` is marked clean, so `dst` mounts as if this filesystem had been
+    /// unmounted; the superblock of this filesystem is left as is. The
+    /// exclusive borrow keeps the filesystem unchanged in the meantime. Only
+    /// allocated blocks are copied; the rest of `dst` is left untouched.
+    pub fn export_to<D: BlockDevice>(&mut self, dst: &mut D) -> Ext4Result {
+        let block_size = self.block_size as u64;
+        if dst.num_blocks()? * (EXT4_DEV_BSIZE as u64) < self.blocks_count * block_size {
+            return Err(Ext4Error::new(EINVAL as _, "destination device too small"));
+        }
+        let used = self.block_usage_bitmap()?;
+        self.flush()?;
+        self.write_superblock()?;
+        self.copy_blocks(dst, &used)?;
+        mark_clean(dst)
+    }
+
+    /// Copies the blocks marked in `used`, see
+    /// [`Ext4Filesystem::block_usage_bitmap`], in runs of up to 64 blocks.
+    fn copy_blocks<D: BlockDevice>(&mut self, dst: &mut D, used: &[u64]) -> Ext4Result {
+        const MAX_RUN: u64 = 64;
+        let block_size = self.block_size as u64;
+        let is_used = |block: u64| used[(block / 64) as usize] & (1 << (block % 64)) != 0;
+
+        let mut buf = vec![0; (MAX_RUN * block_size) as usize];
+        let mut block = 0;
+        while block < self.blocks_count {
+            if !is_used(block) {
+                block += 1;
+                continue;
+            }
+            let mut end = block + 1;
+            while end < self.blocks_count && end - block < MAX_RUN && is_used(end) {
+                end += 1;
+            }
+            let buf = &mut buf[..((end - block) * block_size) as usize];
+            unsafe {
+                ext4_blocks_get_direct(
+                    self.bdev.inner.as_mut(),
+                    buf.as_mut_ptr() as _,
+                    block,
+                    (end - block) as u32,
+                )
+                .context("ext4_blocks_get_direct")?;
+            }
+            dst.write_blocks(block * block_size / EXT4_DEV_BSIZE as u64, buf)?;
+            block = end;
+        }
+        Ok(())
+    }
+}
+
+/// Incompatible features that lwext4 and this crate handle. Any other
+/// incompat bit, including ones unknown here, fails the mount.
+///
+/// With `inline_data`, only regular files are handled inline. Directories
+/// stored inline fail with `ENOTSUP` when they are read or modified.
+const SUPPORTED_INCOMPAT: u32 = Features::INCOMPAT_FILETYPE
+    | Features::INCOMPAT_RECOVER
+    | Features::INCOMPAT_META_BG
+    | Features::INCOMPAT_EXTENTS
+    | Features::INCOMPAT_64BIT
+    | Features::INCOMPAT_FLEX_BG
+    | Features::INCOMPAT_CSUM_SEED
+    | Features::INCOMPAT_INLINE_DATA;
+
+/// Error contexts naming the unsupported incompat features.
+const INCOMPAT_NAMES: [(u32, &str); 9] = [
+    (
+        Features::INCOMPAT_COMPRESSION,
+        "unsupported feature: compression",
+    ),
+    (
+        Features::INCOMPAT_JOURNAL_DEV,
+        "unsupported feature: journal_dev",
+    ),
+    (Features::INCOMPAT_MMP, "unsupported feature: mmp"),
+    (Features::INCOMPAT_EA_INODE, "unsupported feature: ea_inode"),
+    (Features::INCOMPAT_DIRDATA, "unsupported feature: dirdata"),
+    (Features::INCOMPAT_LARGEDIR, "unsupported feature: largedir"),
+    (Features::INCOMPAT_ENCRYPT, "unsupported feature: encrypt"),
+    (Features::INCOMPAT_CASEFOLD, "unsupported feature: casefold"),
+    (0, "unsupported feature: unknown incompat"),
+];
+
+/// Marks the superblock on `dev` clean. lwext4 keeps the on-disk state at
+/// "not clean" while mounted.
+fn mark_clean<D: BlockDevice>(dev: &mut D) -> Ext4Result {
+    const SB_OFFSET: u64 = 1024;
+    const SB_BLOCK: u64 = SB_OFFSET / EXT4_DEV_BSIZE as u64;
+    let mut buf = vec![0; size_of::<ext4_sblock>()];
+    dev.read_blocks(SB_BLOCK, &mut buf)?;
+    let mut sb: ext4_sblock = unsafe { ptr::read_unaligned(buf.as_ptr() as *const _) };
+    sb.state = u16::to_le(EXT4_SUPERBLOCK_STATE_VALID_FS as _);
+    if Features::from_sblock(&sb).has_metadata_csum() {
+        let len = mem::offset_of!(ext4_sblock, checksum) as u32;
+        sb.checksum = u32::to_le(unsafe { ext4_crc32c(u32::MAX, &sb as *const _ as _, len) });
+    }
+    unsafe { ptr::write_unaligned(buf.as_mut_ptr() as *mut _, sb) };
+    dev.write_blocks(SB_BLOCK, &buf)?;
+    Ok(())
+}
+
+/// Rejects devices that don't hold an ext4 filesystem, or use `bigalloc` or
+/// incompat features outside [`SUPPORTED_INCOMPAT`], before `ext4_fs_init`
+/// gets to them, which would fail with less obvious errors or not at all.
+unsafe fn check_superblock(bd: *mut ext4_blockdev) -> Ext4Result {
+    let mut sb: ext4_sblock = unsafe { mem::zeroed() };
+    unsafe { ext4_sb_read(bd, &mut sb) }.context("ext4_sb_read")?;
+    if u16::from_le(sb.magic) != EXT4_SUPERBLOCK_MAGIC as u16 {
+        return Err(Ext4Error::new(EINVAL as _, "bad superblock magic"));
+    }
+    let features = Features::from_sblock(&sb);
+    if features.has_metadata_csum() {
+        // crc32c of everything before the checksum field, seeded with ~0
+        let len = mem::offset_of!(ext4_sblock, checksum) as u32;
+        let csum = unsafe { ext4_crc32c(u32::MAX, &sb as *const _ as _, len) };
+        if csum != u32::from_le(sb.checksum) {
+            return Err(Ext4Error::new(EINVAL as _, "bad superblock checksum"));
+        }
+    }
+    if features.has_bigalloc() {
+        // lwext4 allocates single blocks and would corrupt the cluster
+        // bitmap on the first write
+        return Err(Ext4Error::new(
+            ENOTSUP as _,
+            "unsupported feature: bigalloc",
+        ));
+    }
+    let unsupported = features.incompat & !SUPPORTED_INCOMPAT;
+    if unsupported != 0 {
+        let (_, context) = INCOMPAT_NAMES
+            .into_iter()
+            .find(|&(flag, _)| flag == 0 || unsupported & flag != 0)
+            .unwrap();
+        return Err(Ext4Error::new(ENOTSUP as _, context));
+    }
+    Ok(())
+}
+
+fn is_dot_or_dotdot(name: &str) -> bool {
+    name == "." || name == ".."
 }
 
 impl<Hal: SystemHal, Dev: BlockDevice> Drop for Ext4Filesystem<Hal, Dev> {
@@ -265,6 +1286,9 @@ impl<Hal: SystemHal, Dev: BlockDevice> Drop for Ext4Filesystem<Hal, Dev> {
     }
 }
 
+/// Holds write-back on for the duration of one operation, so that its
+/// metadata updates are written together. Only flushes on drop in
+/// [`WritebackMode::Immediate`].
 pub(crate) struct WritebackGuard {
     bdev: *mut ext4_blockdev,
 }
@@ -279,3 +1303,405 @@ impl Drop for WritebackGuard {
         unsafe { ext4_block_cache_write_back(self.bdev, 0) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec, vec::Vec};
+
+    use crate::{
+        Ext4Error, Features, FileAttr, FsConfig, InodeType, ROOT_INO, WritebackMode,
+        error::Context,
+        ffi::{EEXIST, ENOTSUP, EXT4_INODE_FLAG_INLINE_DATA, ext4_dir_add_entry, ext4_sblock},
+        mock::{MockBlockDevice, testing::*},
+    };
+
+    /// 1 MiB of data with a hole in the second quarter.
+    fn sparse_source(fs: &mut TestFs) -> (u32, Vec<u8>) {
+        let mut data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let src = create_file(fs, "src");
+        fs.write_at(src, &data[..256 << 10], 0).unwrap();
+        fs.write_at(src, &data[512 << 10..], 512 << 10).unwrap();
+        data[256 << 10..512 << 10].fill(0);
+        (src, data)
+    }
+
+    #[test]
+    fn copy_file_range_to_new_file() {
+        let (mut fs, _) = test_fs();
+        let (src, data) = sparse_source(&mut fs);
+        let dst = create_file(&mut fs, "dst");
+        assert_eq!(
+            fs.copy_file_range(src, 0, dst, 0, 1 << 20).unwrap(),
+            1 << 20
+        );
+        assert_eq!(read_all(&mut fs, dst, 1 << 20), data);
+        let extents = fs
+            .with_inode_ref(dst, |inode| inode.fiemap(256 << 10, 256 << 10))
+            .unwrap();
+        assert!(extents.iter().all(|extent| extent.is_hole));
+    }
+
+    #[test]
+    fn copy_file_range_over_existing_data() {
+        let (mut fs, _) = test_fs();
+        let (src, data) = sparse_source(&mut fs);
+        let dst = create_file(&mut fs, "dst");
+        fs.write_at(dst, &vec![0xff; 1 << 20], 0).unwrap();
+        assert_eq!(
+            fs.copy_file_range(src, 0, dst, 0, 1 << 20).unwrap(),
+            1 << 20
+        );
+        assert_eq!(read_all(&mut fs, dst, 1 << 20), data);
+    }
+
+    #[test]
+    fn mount_rejects_bigalloc() {
+        let dev = mkfs();
+        patch_superblock(&dev, |sb| {
+            sb.features_read_only |= u32::to_le(Features::RO_COMPAT_BIGALLOC);
+            sb.log_cluster_size = sb.log_block_size;
+        });
+        let Err(err) = TestFs::new(dev, FsConfig::default()) else {
+            panic!("mounted bigalloc image");
+        };
+        assert_eq!(err.code, ENOTSUP as i32);
+        assert_eq!(err.context, Some("unsupported feature: bigalloc"));
+    }
+
+    #[test]
+    fn inline_directory_is_refused() {
+        let dev = mkfs();
+        patch_superblock(&dev, |sb| {
+            sb.features_incompatible |= u32::to_le(Features::INCOMPAT_INLINE_DATA);
+        });
+        let (mut fs, dev) = mount(dev);
+        let dir = fs
+            .create(ROOT_INO, "dir", InodeType::Directory, 0o755)
+            .unwrap();
+        // What the kernel creates for small directories with `inline_data`
+        fs.with_inode_ref(dir, |inode| {
+            inode.set_flags(inode.flags() | EXT4_INODE_FLAG_INLINE_DATA);
+            Ok(())
+        })
+        .unwrap();
+        fs.flush().unwrap();
+        drop(fs);
+
+        let (mut fs, _) = mount(dev);
+        let not_supported = |res: Result<(), Ext4Error>| res.unwrap_err().code == ENOTSUP as i32;
+        assert!(not_supported(fs.read_dir(dir, 0).map(drop)));
+        assert!(not_supported(fs.lookup(dir, "file").map(drop)));
+        assert!(not_supported(
+            fs.create(dir, "file", InodeType::RegularFile, 0o644)
+                .map(drop)
+        ));
+        assert!(not_supported(fs.unlink(ROOT_INO, "dir")));
+    }
+
+    #[test]
+    fn mount_rejects_incompat_outside_allowlist() {
+        for (flag, context) in [
+            (Features::INCOMPAT_MMP, "unsupported feature: mmp"),
+            (Features::INCOMPAT_LARGEDIR, "unsupported feature: largedir"),
+            (1 << 31, "unsupported feature: unknown incompat"),
+        ] {
+            let dev = mkfs();
+            patch_superblock(&dev, |sb| {
+                sb.features_incompatible |= u32::to_le(flag);
+            });
+            let Err(err) = TestFs::new(dev, FsConfig::default()) else {
+                panic!("mounted with incompat {flag:#x}");
+            };
+            assert_eq!(err.code, ENOTSUP as i32);
+            assert_eq!(err.context, Some(context));
+        }
+    }
+
+    #[test]
+    fn remove_dir_all_deep_tree_with_raw_names() {
+        let (mut fs, _) = test_fs();
+        let free_inodes = fs.stat().unwrap().free_inodes_count;
+        let top = fs
+            .create(ROOT_INO, "top", InodeType::Directory, 0o755)
+            .unwrap();
+        let mut cur = top;
+        for _ in 0..500 {
+            cur = fs.create(cur, "d", InodeType::Directory, 0o755).unwrap();
+        }
+        let file = create_file(&mut fs, "file");
+        let mut dir_ref = fs.inode_ref(cur).unwrap();
+        let mut file_ref = fs.inode_ref(file).unwrap();
+        let name = b"\xff\xfe";
+        unsafe {
+            ext4_dir_add_entry(
+                dir_ref.inner.as_mut(),
+                name.as_ptr() as _,
+                name.len() as _,
+                file_ref.inner.as_mut(),
+            )
+            .context("ext4_dir_add_entry")
+            .unwrap();
+        }
+        file_ref.inc_nlink();
+        drop((dir_ref, file_ref));
+        fs.unlink(ROOT_INO, "file").unwrap();
+
+        fs.remove_dir_all(ROOT_INO, "top").unwrap();
+        assert!(
+            fs.lookup(ROOT_INO, "top")
+                .is_err_and(|err| err.is_not_found())
+        );
+        assert_eq!(fs.stat().unwrap().free_inodes_count, free_inodes);
+    }
+
+    #[test]
+    fn io_stats_counts_cache_lookups() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, &[7; 1024], 0).unwrap();
+        let before = fs.io_stats();
+
+        let mut buf = [0; 1024];
+        // Data is written around the cache
+        assert!(fs.read_at_nowait(ino, &mut buf, 0).is_err());
+        fs.prefetch(ino, 0, 1024).unwrap();
+        let stats = fs.io_stats();
+        assert_eq!(stats.cache_hits, before.cache_hits);
+        assert_eq!(stats.cache_misses, before.cache_misses + 2);
+
+        for _ in 0..2 {
+            assert_eq!(fs.read_at_nowait(ino, &mut buf, 0).unwrap(), 1024);
+        }
+        assert_eq!(buf, [7; 1024]);
+        assert_eq!(fs.io_stats().cache_hits, before.cache_hits + 2);
+    }
+
+    #[test]
+    fn lookup_attr_matches_get_attr() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, &[1; 3000], 0).unwrap();
+
+        let (mut found, mut expected) = (FileAttr::default(), FileAttr::default());
+        fs.lookup_attr(ROOT_INO, "file", &mut found).unwrap();
+        fs.get_attr(ino, &mut expected).unwrap();
+        assert_eq!(format!("{found:?}"), format!("{expected:?}"));
+        assert!(
+            fs.lookup_attr(ROOT_INO, "missing", &mut found)
+                .unwrap_err()
+                .is_not_found()
+        );
+    }
+
+    #[test]
+    fn create_with_owner_keeps_32_bit_ids() {
+        let (mut fs, _) = test_fs();
+        let ino = fs
+            .create_with_owner(
+                ROOT_INO,
+                "file",
+                InodeType::RegularFile,
+                0o644,
+                100_000,
+                70_000,
+            )
+            .unwrap();
+        let mut attr = FileAttr::default();
+        fs.get_attr(ino, &mut attr).unwrap();
+        assert_eq!((attr.uid, attr.gid), (100_000, 70_000));
+    }
+
+    #[test]
+    fn write_at_sync_reaches_device_before_returning() {
+        let (mut fs, dev) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        fs.flush().unwrap();
+
+        dev.reset_counters();
+        fs.write_at(ino, &[1; 10], 0).unwrap();
+        let plain = dev.write_requests();
+        // The inode update is still in the cache
+        assert!(fs.is_dirty());
+        fs.flush().unwrap();
+
+        dev.reset_counters();
+        fs.write_at_sync(ino, &[2; 10], 10).unwrap();
+        assert!(!fs.is_dirty());
+        assert!(dev.write_requests() > plain);
+    }
+
+    #[test]
+    fn deferred_writeback_issues_fewer_writes() {
+        let mut writes = [0; 2];
+        for (mode, writes) in [WritebackMode::Immediate, WritebackMode::Deferred]
+            .into_iter()
+            .zip(&mut writes)
+        {
+            let (mut fs, dev) = test_fs();
+            fs.set_writeback_mode(mode).unwrap();
+            let ino = create_file(&mut fs, "file");
+            fs.flush().unwrap();
+
+            dev.reset_counters();
+            for i in 0..64 {
+                fs.write_at(ino, &[i as u8; 16], i * 16).unwrap();
+            }
+            fs.flush().unwrap();
+            *writes = dev.write_requests();
+        }
+        let [immediate, deferred] = writes;
+        assert!(deferred < immediate, "{deferred} >= {immediate}");
+    }
+
+    #[test]
+    fn create_exclusive_admits_one_creator() {
+        let (mut fs, _) = test_fs();
+        let free_inodes = fs.stat().unwrap().free_inodes_count;
+        // Both creators look `file` up before either adds it
+        let mut dirs = [
+            fs.inode_ref(ROOT_INO).unwrap(),
+            fs.inode_ref(ROOT_INO).unwrap(),
+        ];
+        for dir in &dirs {
+            let found = fs.clone_ref(dir).lookup("file");
+            assert!(found.is_err_and(|err| err.is_not_found()));
+        }
+        let [a, b] = &mut dirs;
+        let ino = fs
+            .create_exclusive_in(a, "file", InodeType::RegularFile, 0o644)
+            .unwrap()
+            .ino();
+        let err = fs
+            .create_exclusive_in(b, "file", InodeType::RegularFile, 0o600)
+            .map(|inode| inode.ino())
+            .unwrap_err();
+        assert_eq!(err.code, EEXIST as i32);
+        drop(dirs);
+
+        assert_eq!(fs.lookup(ROOT_INO, "file").unwrap().entry().ino(), ino);
+        assert_eq!(fs.stat().unwrap().free_inodes_count, free_inodes - 1);
+        let err = fs
+            .create_exclusive(ROOT_INO, "file", InodeType::RegularFile, 0o644)
+            .unwrap_err();
+        assert_eq!(err.code, EEXIST as i32);
+    }
+
+    #[test]
+    fn export_to_mounts_with_same_contents() {
+        let (mut fs, dev) = test_fs();
+        let (src, data) = sparse_source(&mut fs);
+        let state = || {
+            let offset = 1024 + core::mem::offset_of!(ext4_sblock, state);
+            let image = dev.to_image();
+            u16::from_le_bytes([image[offset], image[offset + 1]])
+        };
+        let before = state();
+        let mut copy = MockBlockDevice::new(IMAGE_BLOCKS);
+        fs.export_to(&mut copy).unwrap();
+        // Only the copy is marked clean
+        assert_eq!(state(), before);
+        drop(fs);
+
+        let (mut fs, _) = mount(copy);
+        assert!(fs.check().unwrap().is_clean());
+        assert_eq!(fs.lookup(ROOT_INO, "src").unwrap().entry().ino(), src);
+        assert_eq!(read_all(&mut fs, src, data.len()), data);
+    }
+
+    #[test]
+    fn set_len_above_4gib_survives_remount() {
+        let (mut fs, dev) = test_fs();
+        let ino = create_file(&mut fs, "big");
+        let len = (5 << 30) + 123;
+        fs.set_len(ino, len).unwrap();
+        // Sparse: no block is mapped for the new range
+        let mut attr = FileAttr::default();
+        fs.get_attr(ino, &mut attr).unwrap();
+        assert_eq!(attr.blocks, 0);
+        fs.flush().unwrap();
+        drop(fs);
+
+        let (mut fs, _) = mount(dev);
+        let size = fs.with_inode_ref(ino, |inode| Ok(inode.size())).unwrap();
+        assert_eq!(size, len);
+    }
+
+    #[test]
+    fn features_of_known_image() {
+        let dev = mkfs();
+        patch_superblock(&dev, |sb| {
+            sb.features_compatible = u32::to_le(Features::COMPAT_EXT_ATTR);
+            sb.features_incompatible =
+                u32::to_le(Features::INCOMPAT_FILETYPE | Features::INCOMPAT_EXTENTS);
+            sb.features_read_only = u32::to_le(
+                Features::RO_COMPAT_SPARSE_SUPER
+                    | Features::RO_COMPAT_LARGE_FILE
+                    | Features::RO_COMPAT_HUGE_FILE,
+            );
+        });
+        let (fs, _) = mount(dev);
+        let features = fs.features();
+        assert!(features.has_extents());
+        assert!(features.has_filetype());
+        assert!(features.has_large_file());
+        assert!(features.has_huge_file());
+        assert!(!features.has_64bit());
+        assert!(!features.has_journal());
+        assert!(!features.has_inline_data());
+        assert!(!features.has_metadata_csum());
+        assert!(!features.has_bigalloc());
+    }
+
+    #[test]
+    fn uuid_of_fixture_image() {
+        const UUID: [u8; 16] = [
+            0x6f, 0x1c, 0x2a, 0x7e, 0x3b, 0x94, 0x4d, 0x51, 0x8e, 0x02, 0xa7, 0xc3, 0x5d, 0x10,
+            0xf8, 0x46,
+        ];
+        let (fs, _) = mount(mkfs_with(|info| info.uuid = UUID));
+        assert_eq!(fs.uuid(), UUID);
+        assert_eq!(fs.uuid_string(), "6f1c2a7e-3b94-4d51-8e02-a7c35d10f846");
+    }
+
+    /// Walks the superblock's orphan list, as orphan cleanup would.
+    fn orphans(fs: &mut TestFs) -> Vec<u32> {
+        let mut result = Vec::new();
+        let mut cur = u32::from_le(fs.inner.sb.last_orphan);
+        while cur != 0 {
+            result.push(cur);
+            cur = fs.with_inode_ref(cur, |inode| Ok(inode.dtime())).unwrap();
+        }
+        result
+    }
+
+    #[test]
+    fn unlink_leaves_no_orphan_behind() {
+        let (mut fs, dev) = test_fs();
+        let [a, b] = ["a", "b"].map(|name| {
+            let ino = create_file(&mut fs, name);
+            fs.write_at(ino, &[1; 4096], 0).unwrap();
+            fs.set_len(ino, 100).unwrap();
+            ino
+        });
+        // lwext4 keeps no orphan list of its own, so link both truncated
+        // files as an interrupted kernel truncate would: `a` -> `b`
+        fs.inner.sb.last_orphan = u32::to_le(a);
+        fs.with_inode_ref(a, |inode| {
+            inode.raw_inode_mut().deletion_time = u32::to_le(b);
+            inode.mark_dirty();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(orphans(&mut fs), [a, b]);
+
+        // From the middle of the list, then from its head
+        fs.unlink(ROOT_INO, "b").unwrap();
+        assert_eq!(orphans(&mut fs), [a]);
+        fs.unlink(ROOT_INO, "a").unwrap();
+        fs.flush().unwrap();
+        drop(fs);
+
+        let (mut fs, _) = mount(dev);
+        assert!(orphans(&mut fs).is_empty());
+    }
+}
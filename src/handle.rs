@@ -0,0 +1,105 @@
+use crate::{BlockDevice, Ext4Filesystem, Ext4Result, InodeType, SystemHal};
+
+/// Open file with a cursor, returned by [`Ext4Filesystem::open`].
+///
+/// With the `std` feature enabled this implements [`std::io::Read`],
+/// [`std::io::Write`] and [`std::io::Seek`].
+pub struct File<'a, Hal: SystemHal, Dev: BlockDevice> {
+    fs: &'a mut Ext4Filesystem<Hal, Dev>,
+    ino: u32,
+    pos: u64,
+}
+
+impl<'a, Hal: SystemHal, Dev: BlockDevice> File<'a, Hal, Dev> {
+    pub fn new(fs: &'a mut Ext4Filesystem<Hal, Dev>, ino: u32) -> Self {
+        Self { fs, ino, pos: 0 }
+    }
+
+    pub fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    pub fn size(&mut self) -> Ext4Result<u64> {
+        self.fs.with_inode_ref(self.ino, |inode| Ok(inode.size()))
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Ext4Result<usize> {
+        let read = self.fs.read_at(self.ino, buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+    pub fn write(&mut self, buf: &[u8]) -> Ext4Result<usize> {
+        let written = self.fs.write_at(self.ino, buf, self.pos)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+}
+
+impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
+    pub fn open(&mut self, ino: u32) -> File<'_, Hal, Dev> {
+        File::new(self, ino)
+    }
+
+    /// Creates a node like [`Ext4Filesystem::create`] and opens it.
+    pub fn create_open(
+        &mut self,
+        parent: u32,
+        name: &str,
+        ty: InodeType,
+        mode: u32,
+    ) -> Ext4Result<File<'_, Hal, Dev>> {
+        let ino = self.create(parent, name, ty, mode)?;
+        Ok(File::new(self, ino))
+    }
+}
+
+#[cfg(feature = "std")]
+mod io {
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    use super::File;
+    use crate::{BlockDevice, Ext4Error, SystemHal};
+
+    impl From<Ext4Error> for io::Error {
+        fn from(err: Ext4Error) -> Self {
+            io::Error::from_raw_os_error(err.code)
+        }
+    }
+
+    impl<Hal: SystemHal, Dev: BlockDevice> Read for File<'_, Hal, Dev> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Ok(File::read(self, buf)?)
+        }
+    }
+
+    impl<Hal: SystemHal, Dev: BlockDevice> Write for File<'_, Hal, Dev> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(File::write(self, buf)?)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(self.fs.flush()?)
+        }
+    }
+
+    impl<Hal: SystemHal, Dev: BlockDevice> Seek for File<'_, Hal, Dev> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let new_pos = match pos {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+                SeekFrom::End(off) => self.size()?.checked_add_signed(off),
+            };
+            let new_pos = new_pos.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position")
+            })?;
+            self.pos = new_pos;
+            Ok(new_pos)
+        }
+    }
+}
@@ -1,11 +1,15 @@
-use core::time::Duration;
+use core::{mem::offset_of, time::Duration};
 
-use crate::{SystemHal, ffi::*, util::get_block_size};
+use crate::{Ext4Error, Ext4Result, Features, SystemHal, ffi::*, util::inode_size};
 
 use super::{InodeRef, InodeType};
 
 /// Filesystem node metadata.
+///
+/// With the `serde` feature, timestamps are encoded as `(secs, nanos)`
+/// tuples.
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileAttr {
     /// ID of device containing file
     pub device: u64,
@@ -25,15 +29,71 @@ pub struct FileAttr {
     pub size: u64,
     /// Block size for filesystem I/O
     pub block_size: u64,
-    /// Number of 512B blocks allocated
+    /// Number of 512B blocks allocated, as in `st_blocks`. Inodes with the
+    /// `HUGE_FILE` flag count in filesystem blocks on disk; these are scaled
+    /// to 512B units when read.
     pub blocks: u64,
+    /// Device ID for character and block device nodes, zero otherwise
+    pub rdev: u64,
+    /// Inode generation, which changes when the inode number is reused
+    pub generation: u32,
 
     /// Time of last access
+    #[cfg_attr(feature = "serde", serde(with = "duration_tuple"))]
     pub atime: Duration,
     /// Time of last modification
+    #[cfg_attr(feature = "serde", serde(with = "duration_tuple"))]
     pub mtime: Duration,
     /// Time of last status change
+    #[cfg_attr(feature = "serde", serde(with = "duration_tuple"))]
     pub ctime: Duration,
+    /// Time of creation
+    #[cfg_attr(feature = "serde", serde(with = "duration_tuple"))]
+    pub crtime: Duration,
+}
+
+#[cfg(feature = "serde")]
+mod duration_tuple {
+    use core::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(time: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        (time.as_secs(), time.subsec_nanos()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let (secs, nanos) = <(u64, u32)>::deserialize(deserializer)?;
+        if nanos >= 1_000_000_000 {
+            return Err(D::Error::custom("nanoseconds out of range"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+/// New value for one timestamp in [`InodeRef::set_times`], as in
+/// `utimensat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSpec {
+    /// The current time from [`SystemHal::now`]
+    Now,
+    /// Leave the timestamp unchanged
+    Omit,
+    /// A specific time
+    Set(Duration),
+}
+
+/// Builds a Linux `dev_t` from its major and minor numbers.
+pub fn makedev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+/// Major number of a Linux `dev_t`.
+pub fn major(dev: u32) -> u32 {
+    (dev >> 8) & 0xfff
+}
+/// Minor number of a Linux `dev_t`.
+pub fn minor(dev: u32) -> u32 {
+    (dev & 0xff) | ((dev >> 12) & 0xfff00)
 }
 
 fn encode_time(dur: &Duration) -> (u32, u32) {
@@ -61,9 +121,25 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         self.inode_type() == InodeType::Directory
     }
 
+    /// File size in bytes. For regular files this combines `i_size_lo` and
+    /// `i_size_high`, so sizes past 4 GiB read back in full.
     pub fn size(&self) -> u64 {
         unsafe { ext4_inode_get_size(self.superblock() as *const _ as _, self.inner.inode) }
     }
+    /// Sets both size words. A size past 2 GiB also records the `large_file`
+    /// feature in the superblock, which is written back on unmount.
+    pub(super) fn set_size(&mut self, size: u64) {
+        unsafe {
+            ext4_inode_set_size(self.inner.inode, size);
+            let sb = &mut (*self.inner.fs).sb;
+            if size > i32::MAX as u64
+                && !Features::from_sblock(sb).has_ro_compat(Features::RO_COMPAT_LARGE_FILE)
+            {
+                sb.features_read_only |= u32::to_le(Features::RO_COMPAT_LARGE_FILE);
+            }
+        }
+        self.mark_dirty();
+    }
 
     pub fn mode(&self) -> u32 {
         unsafe { ext4_inode_get_mode(self.superblock() as *const _ as _, self.inner.inode) }
@@ -75,21 +151,109 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         }
     }
 
+    /// Clears the setuid bit, and the setgid bit if group-exec is set, as
+    /// done when an unprivileged caller modifies the file contents.
+    pub fn kill_setid(&mut self) {
+        const S_ISUID: u32 = 0o4000;
+        const S_ISGID: u32 = 0o2000;
+        const S_IXGRP: u32 = 0o0010;
+
+        let mode = self.mode();
+        let mut kill = S_ISUID;
+        if mode & S_IXGRP != 0 {
+            kill |= S_ISGID;
+        }
+        if mode & kill != 0 {
+            self.set_mode(mode & !kill);
+            self.update_ctime();
+        }
+    }
+
     pub fn nlink(&self) -> u16 {
         u16::from_le(self.raw_inode().links_count)
     }
 
-    pub fn uid(&self) -> u16 {
-        u16::from_le(self.raw_inode().uid)
+    /// Owner ID, with the high 16 bits kept in `osd2`.
+    pub fn uid(&self) -> u32 {
+        let inode = self.raw_inode();
+        let high = unsafe { inode.osd2.linux2.uid_high };
+        u16::from_le(inode.uid) as u32 | (u16::from_le(high) as u32) << 16
     }
-    pub fn gid(&self) -> u16 {
-        u16::from_le(self.raw_inode().gid)
+    /// Group ID, with the high 16 bits kept in `osd2`.
+    pub fn gid(&self) -> u32 {
+        let inode = self.raw_inode();
+        let high = unsafe { inode.osd2.linux2.gid_high };
+        u16::from_le(inode.gid) as u32 | (u16::from_le(high) as u32) << 16
     }
 
-    pub fn set_owner(&mut self, uid: u16, gid: u16) {
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
         let inode = self.raw_inode_mut();
-        inode.uid = u16::to_le(uid);
-        inode.gid = u16::to_le(gid);
+        inode.uid = u16::to_le(uid as u16);
+        inode.gid = u16::to_le(gid as u16);
+        inode.osd2.linux2.uid_high = u16::to_le((uid >> 16) as u16);
+        inode.osd2.linux2.gid_high = u16::to_le((gid >> 16) as u16);
+        self.mark_dirty();
+    }
+
+    /// Raw `EXT4_INODE_FLAG_*` flags.
+    pub fn flags(&self) -> u32 {
+        unsafe { ext4_inode_get_flags(self.inner.inode) }
+    }
+    /// Replaces the raw inode flags. Flags describing the on-disk layout,
+    /// such as `EXTENTS` and `INLINE_DATA`, must be preserved by the caller.
+    pub fn set_flags(&mut self, flags: u32) {
+        unsafe { ext4_inode_set_flags(self.inner.inode, flags) };
+        self.mark_dirty();
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.flags() & EXT4_INODE_FLAG_IMMUTABLE != 0
+    }
+    pub fn is_append_only(&self) -> bool {
+        self.flags() & EXT4_INODE_FLAG_APPEND != 0
+    }
+
+    /// Fails with `EPERM` if the inode is immutable or append-only, in which
+    /// case it may be neither truncated, unlinked nor renamed.
+    pub(crate) fn check_removable(&self) -> Ext4Result {
+        if self.is_immutable() || self.is_append_only() {
+            return Err(Ext4Error::new(
+                EPERM as _,
+                "inode is immutable or append-only",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_device(&self) -> bool {
+        matches!(
+            self.inode_type(),
+            InodeType::CharacterDevice | InodeType::BlockDevice
+        )
+    }
+
+    /// Device ID of a device node, in the Linux `dev_t` encoding.
+    pub fn rdev(&self) -> u32 {
+        let blocks = &self.raw_inode().blocks;
+        // Small IDs use the old 16-bit encoding in `i_block[0]`, large ones
+        // the new encoding in `i_block[1]`
+        match u32::from_le(blocks[0]) {
+            0 => u32::from_le(blocks[1]),
+            old => old,
+        }
+    }
+    pub fn set_rdev(&mut self, rdev: u32) {
+        let blocks = &mut self.raw_inode_mut().blocks;
+        blocks.fill(0);
+        if major(rdev) < 256 && minor(rdev) < 256 {
+            // Same bits as the old encoding for small IDs
+            blocks[0] = u32::to_le(rdev);
+        } else {
+            blocks[1] = u32::to_le(rdev);
+        }
+        unsafe {
+            ext4_inode_clear_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS);
+        }
         self.mark_dirty();
     }
 
@@ -115,6 +279,46 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         self.mark_dirty();
     }
 
+    /// Whether the on-disk inode is large enough to hold the extra field
+    /// ending at byte `end`.
+    fn has_extra_field(&self, end: usize) -> bool {
+        const GOOD_OLD_INODE_SIZE: usize = EXT4_GOOD_OLD_INODE_SIZE as usize;
+        inode_size(self.superblock()) as usize > GOOD_OLD_INODE_SIZE
+            && GOOD_OLD_INODE_SIZE + u16::from_le(self.raw_inode().extra_isize) as usize >= end
+    }
+
+    pub fn crtime(&self) -> Duration {
+        if !self.has_extra_field(offset_of!(ext4_inode, crtime_extra) + size_of::<u32>()) {
+            return Duration::ZERO;
+        }
+        let inode = self.raw_inode();
+        decode_time(inode.crtime, inode.crtime_extra)
+    }
+    pub fn set_crtime(&mut self, dur: &Duration) {
+        if !self.has_extra_field(offset_of!(ext4_inode, crtime_extra) + size_of::<u32>()) {
+            return;
+        }
+        let (time, extra) = encode_time(dur);
+        let inode = self.raw_inode_mut();
+        inode.crtime = time;
+        inode.crtime_extra = extra;
+        self.mark_dirty();
+    }
+
+    pub fn generation(&self) -> u32 {
+        unsafe { ext4_inode_get_generation(self.inner.inode) }
+    }
+    pub(crate) fn set_generation(&mut self, generation: u32) {
+        unsafe { ext4_inode_set_generation(self.inner.inode, generation) };
+        self.mark_dirty();
+    }
+
+    /// Deletion time in seconds since the epoch, zero while the inode is in
+    /// use. Orphan inodes reuse the field to link the orphan list.
+    pub fn dtime(&self) -> u32 {
+        u32::from_le(self.raw_inode().deletion_time)
+    }
+
     pub fn update_atime(&mut self) {
         if let Some(dur) = Hal::now() {
             self.set_atime(&dur);
@@ -131,23 +335,52 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         }
     }
 
+    /// Updates the access and modification times, bumping ctime if either
+    /// changes. [`TimeSpec::Now`] acts like [`TimeSpec::Omit`] when the HAL
+    /// has no clock.
+    pub fn set_times(&mut self, atime: TimeSpec, mtime: TimeSpec) {
+        let resolve = |spec| match spec {
+            TimeSpec::Now => Hal::now(),
+            TimeSpec::Omit => None,
+            TimeSpec::Set(dur) => Some(dur),
+        };
+        let atime = resolve(atime);
+        let mtime = resolve(mtime);
+        if let Some(atime) = &atime {
+            self.set_atime(atime);
+        }
+        if let Some(mtime) = &mtime {
+            self.set_mtime(mtime);
+        }
+        if atime.is_some() || mtime.is_some() {
+            self.update_ctime();
+        }
+    }
+
     pub fn get_attr(&self, attr: &mut FileAttr) {
         attr.device = 0;
-        attr.ino = u32::from_le(self.inner.index);
+        attr.ino = self.ino();
         attr.nlink = self.nlink() as _;
         attr.mode = self.mode();
         attr.node_type = self.inode_type();
-        attr.uid = self.uid() as _;
-        attr.gid = self.gid() as _;
+        attr.uid = self.uid();
+        attr.gid = self.gid();
         attr.size = self.size();
-        attr.block_size = get_block_size(self.superblock()) as _;
+        attr.block_size = self.block_size() as _;
         attr.blocks = unsafe {
             ext4_inode_get_blocks_count(self.superblock() as *const _ as _, self.inner.inode)
         };
+        attr.rdev = if self.is_device() {
+            self.rdev() as _
+        } else {
+            0
+        };
+        attr.generation = self.generation();
 
         let inode = self.raw_inode();
         attr.atime = decode_time(inode.access_time, inode.atime_extra);
         attr.mtime = decode_time(inode.modification_time, inode.mtime_extra);
         attr.ctime = decode_time(inode.change_inode_time, inode.ctime_extra);
+        attr.crtime = self.crtime();
     }
 }
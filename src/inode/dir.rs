@@ -1,11 +1,40 @@
-use core::{mem, slice};
+use core::{marker::PhantomData, mem, slice, str::Utf8Error};
 
-use crate::{Ext4Result, SystemHal, error::Context, ffi::*, util::revision_tuple};
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    Ext4Error, Ext4Result, SystemHal,
+    error::{Context, ENAMETOOLONG},
+    ffi::*,
+    util::revision_tuple,
+};
 
 use super::{InodeRef, InodeType};
 
+/// Checks that `name` is usable as a single directory entry name.
+pub(crate) fn validate_name(name: &[u8]) -> Ext4Result {
+    if name.len() > EXT4_DIRECTORY_FILENAME_LEN as usize {
+        return Err(Ext4Error::new(ENAMETOOLONG as _, "file name too long"));
+    }
+    if name.is_empty() || name.iter().any(|&c| c == b'/' || c == 0) {
+        return Err(Ext4Error::new(EINVAL as _, "invalid file name"));
+    }
+    Ok(())
+}
+
 impl<Hal: SystemHal> InodeRef<Hal> {
-    pub fn read_dir(mut self, offset: u64) -> Ext4Result<DirReader<Hal>> {
+    /// Fails with `ENOTSUP` for directories whose entries are stored inline.
+    /// lwext4's directory code only walks block-mapped directories, and would
+    /// read the inline entries as garbage or corrupt them on insert.
+    fn check_block_dir(&self) -> Ext4Result {
+        if self.is_dir() && self.has_inline_data() {
+            return Err(Ext4Error::new(ENOTSUP as _, "inline directory"));
+        }
+        Ok(())
+    }
+
+    pub fn read_dir<'a>(mut self, offset: u64) -> Ext4Result<DirReader<'a, Hal>> {
+        self.check_block_dir()?;
         unsafe {
             let mut iter = mem::zeroed();
             ext4_dir_iterator_init(&mut iter, self.inner.as_mut(), offset)
@@ -14,11 +43,31 @@ impl<Hal: SystemHal> InodeRef<Hal> {
             Ok(DirReader {
                 parent: self,
                 inner: iter,
+                yielded: false,
+                skip_dots: false,
+                _phantom: PhantomData,
             })
         }
     }
 
-    pub fn lookup(mut self, name: &str) -> Ext4Result<DirLookupResult<Hal>> {
+    /// Looks up `name` in this directory.
+    ///
+    /// Entries are matched on the full name, so for indexed directories two
+    /// names sharing an htree hash still resolve to their own inodes: lwext4
+    /// compares every entry in the hashed leaf block and follows the collision
+    /// chain into the next leaf.
+    ///
+    /// `ENOENT` is only returned once every candidate block has been read, so
+    /// it is a definite miss that may be cached. A read failure partway
+    /// through the scan is reported with its own error code instead.
+    pub fn lookup<'a>(self, name: &str) -> Ext4Result<DirLookupResult<'a, Hal>> {
+        self.lookup_bytes(name.as_bytes())
+    }
+
+    /// Like [`InodeRef::lookup`], for names that need not be UTF-8.
+    pub(crate) fn lookup_bytes<'a>(mut self, name: &[u8]) -> Ext4Result<DirLookupResult<'a, Hal>> {
+        validate_name(name)?;
+        self.check_block_dir()?;
         unsafe {
             let mut result = mem::zeroed();
             ext4_dir_find_entry(
@@ -32,26 +81,53 @@ impl<Hal: SystemHal> InodeRef<Hal> {
             Ok(DirLookupResult {
                 parent: self,
                 inner: result,
+                _phantom: PhantomData,
             })
         }
     }
 
-    pub fn has_children(self) -> Ext4Result<bool> {
-        if self.inode_type() != InodeType::Directory {
-            return Ok(false);
+    /// Whether the directory has no entries besides `.` and `..`. Stops at
+    /// the first other entry.
+    pub fn is_empty_dir(self) -> Ext4Result<bool> {
+        if !self.is_dir() {
+            return Err(Ext4Error::new(ENOTDIR as _, None));
         }
-        let mut reader = self.read_dir(0)?;
-        while let Some(curr) = reader.current() {
-            let name = curr.name();
-            if name != b"." && name != b".." {
-                return Ok(true);
-            }
+        Ok(self.read_dir(0)?.skip_dots()?.current().is_none())
+    }
+
+    /// Number of entries in the directory besides `.` and `..`.
+    pub fn count_entries(self) -> Ext4Result<usize> {
+        if !self.is_dir() {
+            return Err(Ext4Error::new(ENOTDIR as _, None));
+        }
+        let mut count = 0;
+        let mut reader = self.read_dir(0)?.skip_dots()?;
+        while reader.current().is_some() {
+            count += 1;
             reader.step()?;
         }
-        Ok(false)
+        Ok(count)
     }
 
+    pub fn has_children(self) -> Ext4Result<bool> {
+        if !self.is_dir() {
+            return Ok(false);
+        }
+        Ok(!self.is_empty_dir()?)
+    }
+
+    /// Links `entry` under `name`. On `filetype` filesystems lwext4 fills the
+    /// entry's type byte from the child's mode, which [`alloc_inode`] has
+    /// already set, so readers get the type without fetching the inode.
+    ///
+    /// On `metadata_csum` filesystems lwext4 also refreshes the checksum of
+    /// each directory block it modifies, including htree index blocks; the
+    /// same holds for [`InodeRef::remove_entry`].
+    ///
+    /// [`alloc_inode`]: crate::Ext4Filesystem::alloc_inode
     pub(crate) fn add_entry(&mut self, name: &str, entry: &mut InodeRef<Hal>) -> Ext4Result {
+        self.check_add_entry(name)?;
+        entry.check_nlink_limit()?;
         unsafe {
             ext4_dir_add_entry(
                 self.inner.as_mut(),
@@ -64,7 +140,8 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         entry.inc_nlink();
         Ok(())
     }
-    pub(crate) fn remove_entry(&mut self, name: &str, entry: &mut InodeRef<Hal>) -> Ext4Result {
+    pub(crate) fn remove_entry(&mut self, name: &[u8], entry: &mut InodeRef<Hal>) -> Ext4Result {
+        self.check_remove_entry(entry)?;
         unsafe {
             ext4_dir_remove_entry(
                 self.inner.as_mut(),
@@ -76,13 +153,39 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         entry.dec_nlink();
         Ok(())
     }
+
+    /// Checks everything [`InodeRef::add_entry`] checks about the directory
+    /// and `name`, without changing anything, so that callers can fail before
+    /// making other changes.
+    pub(crate) fn check_add_entry(&self, name: &str) -> Ext4Result {
+        validate_name(name.as_bytes())?;
+        self.check_block_dir()?;
+        if self.is_immutable() {
+            return Err(Ext4Error::new(EPERM as _, "directory is immutable"));
+        }
+        Ok(())
+    }
+    /// Checks everything [`InodeRef::remove_entry`] checks, without changing
+    /// anything.
+    pub(crate) fn check_remove_entry(&self, entry: &InodeRef<Hal>) -> Ext4Result {
+        self.check_block_dir()?;
+        if self.is_immutable() {
+            return Err(Ext4Error::new(EPERM as _, "directory is immutable"));
+        }
+        entry.check_removable()
+    }
 }
 
-pub struct DirLookupResult<Hal: SystemHal> {
+/// Result of [`InodeRef::lookup`].
+///
+/// Points into the block cache of the filesystem it was read from, and so
+/// borrows that filesystem for `'a`.
+pub struct DirLookupResult<'a, Hal: SystemHal> {
     parent: InodeRef<Hal>,
     inner: ext4_dir_search_result,
+    _phantom: PhantomData<&'a mut ()>,
 }
-impl<Hal: SystemHal> DirLookupResult<Hal> {
+impl<Hal: SystemHal> DirLookupResult<'_, Hal> {
     pub fn entry(&mut self) -> DirEntry {
         DirEntry {
             inner: unsafe { &mut *(self.inner.dentry as *mut _) },
@@ -90,7 +193,7 @@ impl<Hal: SystemHal> DirLookupResult<Hal> {
         }
     }
 }
-impl<Hal: SystemHal> Drop for DirLookupResult<Hal> {
+impl<Hal: SystemHal> Drop for DirLookupResult<'_, Hal> {
     fn drop(&mut self) {
         unsafe {
             ext4_dir_destroy_result(self.parent.inner.as_mut(), &mut self.inner);
@@ -153,6 +256,15 @@ impl DirEntry<'_> {
     pub fn name(&self) -> &[u8] {
         self.inner.name(self.sb)
     }
+    /// Returns the name as UTF-8. ext4 does not require names to be valid
+    /// UTF-8, so this may fail where [`DirEntry::name`] does not.
+    pub fn name_str(&self) -> Result<&str, Utf8Error> {
+        core::str::from_utf8(self.name())
+    }
+    /// Returns the name, replacing invalid UTF-8 sequences.
+    pub fn name_string(&self) -> String {
+        String::from_utf8_lossy(self.name()).into_owned()
+    }
 
     pub fn inode_type(&self) -> InodeType {
         self.inner.inode_type(self.sb)
@@ -174,12 +286,53 @@ impl DirEntry<'_> {
     }
 }
 
+/// Directory entry detached from the [`DirReader`] that produced it.
+#[derive(Clone, Debug)]
+pub struct OwnedDirEntry {
+    pub ino: u32,
+    pub name: Vec<u8>,
+    pub inode_type: InodeType,
+    /// Offset of the entry after this one, to pass to
+    /// [`InodeRef::read_dir`] to resume reading after it.
+    pub next_offset: u64,
+}
+
 /// Reader returned by [`InodeRef::read_dir`].
-pub struct DirReader<Hal: SystemHal> {
+///
+/// Entries can be walked either manually with [`DirReader::current`] and
+/// [`DirReader::step`], or through the [`Iterator`] implementation; mixing the
+/// two on the same reader is not supported. Like [`DirLookupResult`], the
+/// reader borrows the filesystem for `'a`.
+pub struct DirReader<'a, Hal: SystemHal> {
     parent: InodeRef<Hal>,
     inner: ext4_dir_iter,
+    yielded: bool,
+    skip_dots: bool,
+    _phantom: PhantomData<&'a mut ()>,
 }
-impl<Hal: SystemHal> DirReader<Hal> {
+impl<Hal: SystemHal> DirReader<'_, Hal> {
+    /// Makes the reader pass over the `.` and `..` entries, for callers that
+    /// synthesize them. This applies to [`DirReader::current`],
+    /// [`DirReader::step`] and the iterator alike.
+    pub fn skip_dots(mut self) -> Ext4Result<Self> {
+        self.skip_dots = true;
+        self.skip_dot_entries()?;
+        Ok(self)
+    }
+
+    fn skip_dot_entries(&mut self) -> Ext4Result {
+        while self.skip_dots
+            && self
+                .current()
+                .is_some_and(|entry| matches!(entry.name(), b"." | b".."))
+        {
+            unsafe {
+                ext4_dir_iterator_next(&mut self.inner).context("ext4_dir_iterator_next")?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn current(&self) -> Option<DirEntry> {
         if self.inner.curr.is_null() {
             return None;
@@ -195,18 +348,132 @@ impl<Hal: SystemHal> DirReader<Hal> {
             unsafe {
                 ext4_dir_iterator_next(&mut self.inner).context("ext4_dir_iterator_next")?;
             }
+            self.skip_dot_entries()?;
         }
         Ok(())
     }
 
+    /// Byte offset of [`DirReader::current`] within the directory, usable as
+    /// a `telldir` cookie for [`DirReader::seek`] or [`InodeRef::read_dir`].
+    ///
+    /// A cookie stays valid while the entries before it are unchanged. ext4
+    /// reclaims a removed entry by growing the record before it, so a cookie
+    /// for a removed entry can land on the stale record left behind and
+    /// yield it again, or fail the seek with `EIO`. Offsets at a block
+    /// boundary are always valid.
     pub fn offset(&self) -> u64 {
         self.inner.curr_off
     }
+    /// Moves the reader to the entry at `offset`, as returned by
+    /// [`DirReader::offset`], like `seekdir`. Seeking past the end leaves the
+    /// reader at the end.
+    pub fn seek(&mut self, offset: u64) -> Ext4Result {
+        unsafe {
+            ext4_dir_iterator_fini(&mut self.inner);
+            self.inner = mem::zeroed();
+            ext4_dir_iterator_init(&mut self.inner, self.parent.inner.as_mut(), offset)
+                .context("ext4_dir_iterator_init")?;
+        }
+        self.yielded = false;
+        self.skip_dot_entries()
+    }
+    /// Offset of the entry after [`DirReader::current`], or the current offset
+    /// once the end is reached.
+    pub fn next_offset(&self) -> u64 {
+        self.offset() + self.current().map_or(0, |entry| entry.len() as u64)
+    }
 }
-impl<Hal: SystemHal> Drop for DirReader<Hal> {
+impl<Hal: SystemHal> Iterator for DirReader<'_, Hal> {
+    type Item = Ext4Result<OwnedDirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Step lazily so that errors are reported for the entry they affect
+        let stepped = if mem::take(&mut self.yielded) {
+            self.step()
+        } else {
+            Ok(())
+        };
+        if let Err(err) = stepped {
+            return Some(Err(err));
+        }
+        let next_offset = self.next_offset();
+        let entry = self.current()?;
+        let entry = OwnedDirEntry {
+            ino: entry.ino(),
+            name: entry.name().to_vec(),
+            inode_type: entry.inode_type(),
+            next_offset,
+        };
+        self.yielded = true;
+        Some(Ok(entry))
+    }
+}
+
+impl<Hal: SystemHal> Drop for DirReader<'_, Hal> {
     fn drop(&mut self) {
         unsafe {
             ext4_dir_iterator_fini(&mut self.inner);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec::Vec};
+
+    use super::DirReader;
+    use crate::{InodeType, ROOT_INO, ffi::*, mock::testing::*};
+
+    fn long_name(i: usize) -> String {
+        format!("{i:0>200}")
+    }
+
+    #[test]
+    fn rename_into_immutable_dir_keeps_source() {
+        let (mut fs, _) = test_fs();
+        let ino = fs
+            .create(ROOT_INO, "file", InodeType::RegularFile, 0o644)
+            .unwrap();
+        let dir = fs
+            .create(ROOT_INO, "dir", InodeType::Directory, 0o755)
+            .unwrap();
+        fs.with_inode_ref(dir, |inode| {
+            inode.set_flags(inode.flags() | EXT4_INODE_FLAG_IMMUTABLE);
+            Ok(())
+        })
+        .unwrap();
+
+        let err = fs.rename(ROOT_INO, "file", dir, "file").unwrap_err();
+        assert_eq!(err.code, EPERM as i32);
+        assert_eq!(fs.lookup(ROOT_INO, "file").unwrap().entry().ino(), ino);
+        assert_eq!(
+            fs.with_inode_ref(ino, |inode| Ok(inode.nlink())).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn seek_back_rereads_same_entries() {
+        let (mut fs, _) = test_fs();
+        let dir = fs
+            .create(ROOT_INO, "dir", InodeType::Directory, 0o755)
+            .unwrap();
+        for i in 0..40 {
+            fs.create(dir, &long_name(i), InodeType::RegularFile, 0o644)
+                .unwrap();
+        }
+
+        let names = |reader: &mut DirReader<'_, _>| {
+            reader.map(|entry| entry.unwrap().name).collect::<Vec<_>>()
+        };
+        let mut reader = fs.read_dir(dir, 0).unwrap();
+        let cookie = reader.nth(20).unwrap().unwrap().next_offset;
+        let rest = names(&mut reader);
+        assert_eq!(rest.len(), 42 - 21);
+
+        reader.seek(cookie).unwrap();
+        assert_eq!(names(&mut reader), rest);
+        drop(reader);
+        assert_eq!(names(&mut fs.read_dir(dir, cookie).unwrap()), rest);
+    }
+}
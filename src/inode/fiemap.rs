@@ -0,0 +1,253 @@
+use alloc::{vec, vec::Vec};
+
+use super::InodeRef;
+
+use crate::{
+    Ext4Error, Ext4Result, SystemHal,
+    ffi::{EIO, ENXIO, EXT4_INODE_FLAG_EXTENTS, ext4_inode_has_flag},
+};
+
+/// Magic number of an extent tree node header.
+const EXTENT_MAGIC: u16 = 0xF30A;
+/// Size of an extent tree node header, and of each entry after it.
+const EXTENT_ENTRY_LEN: usize = 12;
+/// One past the last logical block.
+const END_BLOCK: u64 = 1 << 32;
+
+/// A run of logical blocks, returned by [`InodeRef::fiemap`]. All fields are
+/// in filesystem blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block of the run
+    pub logical: u64,
+    /// First physical block of the run, zero for holes
+    pub physical: u64,
+    /// Number of blocks in the run
+    pub length: u64,
+    /// Whether the run is unallocated
+    pub is_hole: bool,
+}
+
+impl<Hal: SystemHal> InodeRef<Hal> {
+    /// Maps the byte range `start..start + len`, clamped to the file size, to
+    /// physical blocks. Physically contiguous blocks are merged into one
+    /// extent, and unallocated blocks are reported as holes.
+    pub fn fiemap(&mut self, start: u64, len: u64) -> Ext4Result<Vec<Extent>> {
+        let block_size = self.block_size() as u64;
+        let end = start.saturating_add(len).min(self.size());
+        let mut extents: Vec<Extent> = Vec::new();
+        if start >= end || self.has_inline_data() {
+            return Ok(extents);
+        }
+
+        let mut block = start / block_size;
+        let end_block = end.div_ceil(block_size);
+        while block < end_block {
+            let (fblock, count) = self.map_run(block as u32, end_block - block)?;
+            let is_hole = fblock == 0;
+            if let Some(last) = extents.last_mut()
+                && last.is_hole == is_hole
+                && (is_hole || last.physical + last.length == fblock)
+            {
+                last.length += count;
+            } else {
+                extents.push(Extent {
+                    logical: block,
+                    physical: fblock,
+                    length: count,
+                    is_hole,
+                });
+            }
+            block += count;
+        }
+        Ok(extents)
+    }
+
+    /// Number of bytes from `pos` that map to a single physically contiguous
+    /// run of blocks, or to a single hole, so that a caller can size one
+    /// device read for them. The run is cut at EOF, and is `0` past it.
+    pub fn contiguous_len(&mut self, pos: u64) -> Ext4Result<u64> {
+        let size = self.size();
+        if pos >= size {
+            return Ok(0);
+        }
+        if self.has_inline_data() {
+            return Ok(size - pos);
+        }
+
+        let block_size = self.block_size() as u64;
+        let first = pos / block_size;
+        let last = size.div_ceil(block_size);
+        let (start, count) = self.map_run(first as u32, last - first)?;
+        let mut block = first + count;
+        // Adjacent extents may still be physically contiguous
+        while block < last {
+            let (fblock, count) = self.map_run(block as u32, last - block)?;
+            let contiguous = match start {
+                0 => fblock == 0,
+                start => fblock == start + (block - first),
+            };
+            if !contiguous {
+                break;
+            }
+            block += count;
+        }
+        Ok((block * block_size).min(size) - pos)
+    }
+
+    /// Returns the offset of the first hole at or after `from`, like
+    /// `lseek(SEEK_HOLE)`. The end of the file counts as a hole.
+    pub fn seek_hole(&mut self, from: u64) -> Ext4Result<u64> {
+        self.seek_extent(from, true)
+    }
+
+    /// Returns the offset of the first data at or after `from`, like
+    /// `lseek(SEEK_DATA)`. Fails with `ENXIO` if only holes follow.
+    pub fn seek_data(&mut self, from: u64) -> Ext4Result<u64> {
+        self.seek_extent(from, false)
+    }
+
+    fn seek_extent(&mut self, from: u64, hole: bool) -> Ext4Result<u64> {
+        let size = self.size();
+        if from >= size {
+            return Err(Ext4Error::new(ENXIO as _, None));
+        }
+        if self.has_inline_data() {
+            // Inline data has no holes
+            return Ok(if hole { size } else { from });
+        }
+
+        let block_size = self.block_size() as u64;
+        let found = self
+            .fiemap(from, size - from)?
+            .into_iter()
+            .find(|extent| extent.is_hole == hole);
+        match found {
+            Some(extent) => Ok((extent.logical * block_size).max(from)),
+            None if hole => Ok(size),
+            None => Err(Ext4Error::new(ENXIO as _, None)),
+        }
+    }
+
+    /// Maps up to `max` blocks from `block`, returning the first physical
+    /// block, zero for a hole, and the length of the run, which is at least
+    /// one block. Runs of extent-mapped files span whole extents or holes;
+    /// block-mapped files are mapped one block at a time.
+    fn map_run(&mut self, block: u32, max: u64) -> Ext4Result<(u64, u64)> {
+        if !unsafe { ext4_inode_has_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS) } {
+            return Ok((self.get_inode_fblock(block)?, 1));
+        }
+        let max_blocks = max.min(u32::MAX as u64) as u32;
+        // Also gives the length of unwritten extents, reported as holes
+        let (fblock, count) = self.get_extent_blocks(block, max_blocks, false)?;
+        if count > 0 {
+            return Ok((fblock, (count as u64).min(max)));
+        }
+        let next = self.next_mapped_block(block)?;
+        Ok((0, (next - block as u64).clamp(1, max)))
+    }
+
+    /// Returns a lower bound on the first mapped logical block after the
+    /// unmapped `block`: the start of the next extent, or of the next subtree
+    /// when the leaf holding `block` has none past it.
+    fn next_mapped_block(&mut self, block: u32) -> Ext4Result<u64> {
+        let mut node: Vec<u8> = self
+            .raw_inode()
+            .blocks
+            .iter()
+            .flat_map(|word| word.to_ne_bytes())
+            .collect();
+        let u16_at = |node: &[u8], off: usize| u16::from_le_bytes([node[off], node[off + 1]]);
+        let u32_at =
+            |node: &[u8], off: usize| u32::from_le_bytes(node[off..off + 4].try_into().unwrap());
+        let mut next = END_BLOCK;
+        loop {
+            let entries = u16_at(&node, 2) as usize;
+            if u16_at(&node, 0) != EXTENT_MAGIC || (entries + 1) * EXTENT_ENTRY_LEN > node.len() {
+                return Err(Ext4Error::new(EIO as _, "bad extent header"));
+            }
+            let first_block = |i: usize| u32_at(&node, (i + 1) * EXTENT_ENTRY_LEN) as u64;
+            // Entries are sorted by their first logical block
+            let after = (0..entries)
+                .find(|&i| first_block(i) > block as u64)
+                .unwrap_or(entries);
+            if after < entries {
+                next = first_block(after);
+            }
+            if u16_at(&node, 6) == 0 || after == 0 {
+                // No extent covers `block`, so the next one starts after it
+                return Ok(next);
+            }
+            let entry = after * EXTENT_ENTRY_LEN;
+            let leaf = u32_at(&node, entry + 4) as u64 | (u16_at(&node, entry + 8) as u64) << 32;
+            let block_size = self.block_size() as u64;
+            node = vec![0; block_size as usize];
+            self.read_bytes(leaf * block_size, &mut node)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::*;
+
+    #[test]
+    fn fiemap_reports_holes_of_sparse_file() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        let block_size = fs.block_size() as u64;
+        // Ten one-block extents, more than fit in the inode, with a hole
+        // after each
+        for i in 0..10 {
+            fs.write_at(ino, &[1; 16], 2 * i * block_size).unwrap();
+        }
+        fs.set_len(ino, 100 * block_size).unwrap();
+
+        let extents = fs
+            .with_inode_ref(ino, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        assert_eq!(extents.len(), 20, "{extents:?}");
+        for (i, extent) in extents.iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(extent.logical, i);
+            assert_eq!(extent.is_hole, i % 2 == 1);
+            assert_eq!(extent.physical == 0, extent.is_hole);
+            let length = if i == 19 { 100 - 19 } else { 1 };
+            assert_eq!(extent.length, length, "{extent:?}");
+        }
+
+        let (hole, data) = fs
+            .with_inode_ref(ino, |inode| {
+                Ok((inode.seek_hole(0)?, inode.seek_data(block_size + 1)?))
+            })
+            .unwrap();
+        assert_eq!(hole, block_size);
+        assert_eq!(data, 2 * block_size);
+        let err = fs
+            .with_inode_ref(ino, |inode| inode.seek_data(20 * block_size))
+            .unwrap_err();
+        assert_eq!(err.code, ENXIO as i32);
+    }
+
+    #[test]
+    fn contiguous_len_stops_at_first_extent() {
+        let (mut fs, _) = test_fs();
+        let a = create_file(&mut fs, "a");
+        let b = create_file(&mut fs, "b");
+        // `b` in between leaves `a` in two extents
+        fs.write_at(a, &[1; 5000], 0).unwrap();
+        fs.write_at(b, &[2; 1024], 0).unwrap();
+        fs.write_at(a, &[1; 3000], 5000).unwrap();
+        let extents = fs
+            .with_inode_ref(a, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        assert_eq!(extents.len(), 2, "{extents:?}");
+
+        let first = extents[0].length * fs.block_size() as u64;
+        assert_eq!(fs.contiguous_len(a, 0).unwrap(), first);
+        assert_eq!(fs.contiguous_len(a, 100).unwrap(), first - 100);
+        assert_eq!(fs.contiguous_len(a, first).unwrap(), 8000 - first);
+        assert_eq!(fs.contiguous_len(a, 8000).unwrap(), 0);
+    }
+}
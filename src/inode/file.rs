@@ -3,10 +3,15 @@ use core::{
     slice,
 };
 
-use super::InodeRef;
+use alloc::vec;
+
+use super::{InodeRef, inline::INLINE_DATA_LEN};
 
 use crate::{
-    Ext4Result, InodeType, SystemHal, WritebackGuard, error::Context, ffi::*, util::get_block_size,
+    Ext4Error, Ext4Result, InodeType, SystemHal, WritebackGuard,
+    error::{Context, EAGAIN, EINTR, ENAMETOOLONG},
+    ffi::*,
+    util::max_file_size,
 };
 
 fn take<'a>(buf: &mut &'a [u8], cnt: usize) -> &'a [u8] {
@@ -22,8 +27,109 @@ fn take_mut<'a>(buf: &mut &'a mut [u8], cnt: usize) -> &'a mut [u8] {
     first
 }
 
+/// Buffer state bit from `ext4_bcache.h`.
+const BC_UPTODATE: i32 = 1 << 0;
+
+/// Drops cached copies of blocks that are about to be written directly, so
+/// that [`InodeRef::read_at_nowait`] never serves stale data.
+unsafe fn invalidate_cached(bdev: *mut ext4_blockdev, start: u64, count: u32) {
+    unsafe { ext4_bcache_invalidate_lba((*bdev).bc, start, count) };
+}
+
+/// Copies `out.len()` bytes at `offset` in block `fblock` from the block
+/// cache. Returns whether the cache held an up-to-date copy.
+unsafe fn read_cached(bc: *mut ext4_bcache, fblock: u64, offset: usize, out: &mut [u8]) -> bool {
+    unsafe {
+        let mut b: ext4_block = mem::zeroed();
+        let cached = ext4_bcache_find_get(bc, &mut b, fblock);
+        if cached.is_null() {
+            return false;
+        }
+        let uptodate = (*cached).flags & BC_UPTODATE != 0;
+        if uptodate {
+            out.copy_from_slice(slice::from_raw_parts(b.data.add(offset), out.len()));
+        }
+        ext4_bcache_free(bc, &mut b);
+        uptodate
+    }
+}
+
+/// Block cache lookups of file data blocks, as reported by
+/// [`Ext4Filesystem::io_stats`](crate::Ext4Filesystem::io_stats).
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// The last extent resolved by [`InodeRef::map_block`], so that consecutive
+/// blocks of the same extent don't need another tree walk.
+#[derive(Default)]
+struct ExtentCache {
+    logical: u32,
+    physical: u64,
+    len: u32,
+}
+
 impl<Hal: SystemHal> InodeRef<Hal> {
-    fn get_inode_fblock(&mut self, block: u32) -> Ext4Result<u64> {
+    /// Like [`InodeRef::get_inode_fblock`], but answers from `cache` when
+    /// possible and refills it with up to `max_blocks` blocks of the extent
+    /// containing `block` otherwise.
+    fn map_block(
+        &mut self,
+        cache: &mut ExtentCache,
+        block: u32,
+        max_blocks: u32,
+    ) -> Ext4Result<u64> {
+        if block.wrapping_sub(cache.logical) < cache.len {
+            return Ok(cache.physical + (block - cache.logical) as u64);
+        }
+        if !unsafe { ext4_inode_has_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS) } {
+            return self.get_inode_fblock(block);
+        }
+        let (fblock, count) = self.get_extent_blocks(block, max_blocks, false)?;
+        if fblock != 0 {
+            *cache = ExtentCache {
+                logical: block,
+                physical: fblock,
+                len: count.max(1),
+            };
+        }
+        Ok(fblock)
+    }
+
+    /// Maps up to `max_blocks` blocks from `block` in an extent-mapped file,
+    /// returning the first physical block and the length of the run. With
+    /// `create`, a hole is filled with newly allocated blocks; otherwise it
+    /// yields `(0, 0)`.
+    pub(super) fn get_extent_blocks(
+        &mut self,
+        block: u32,
+        max_blocks: u32,
+        create: bool,
+    ) -> Ext4Result<(u64, u32)> {
+        unsafe {
+            let mut fblock = 0u64;
+            let mut count = 0u32;
+            ext4_extent_get_blocks(
+                self.inner.as_mut(),
+                block,
+                max_blocks.max(1),
+                &mut fblock,
+                create,
+                &mut count,
+            )
+            .context("ext4_extent_get_blocks")?;
+            Ok((fblock, count))
+        }
+    }
+
+    /// Whether this is a symlink whose target is stored in `i_block`.
+    fn is_inline_symlink(&self) -> bool {
+        self.inode_type() == InodeType::Symlink && self.size() < INLINE_DATA_LEN as u64
+    }
+
+    pub(super) fn get_inode_fblock(&mut self, block: u32) -> Ext4Result<u64> {
         unsafe {
             let mut fblock = 0u64;
             ext4_fs_get_inode_dblk_idx(self.inner.as_mut(), block, &mut fblock, true)
@@ -49,7 +155,80 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         }
     }
 
-    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Ext4Result<()> {
+    /// Maps `count` blocks from `start` up front. lwext4 allocates each one
+    /// with the previous block as its goal, so doing this before any data is
+    /// written keeps the run contiguous instead of interleaving it with other
+    /// files' appends. Blocks already mapped are kept as they are.
+    ///
+    /// Blocks past EOF stay allocated until the file is shrunk or removed.
+    /// They are zeroed as they are allocated, since growing the file with
+    /// [`InodeRef::set_len`] or a write past EOF later brings them inside it
+    /// as regular mapped blocks. Only extent-mapped files are preallocated.
+    fn prealloc_blocks(&mut self, start: u32, count: u32) -> Ext4Result {
+        if !unsafe { ext4_inode_has_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS) } {
+            return Ok(());
+        }
+        let end = start.saturating_add(count);
+        let mut block = start;
+        while block < end {
+            // Look up first so that only blocks allocated here are zeroed
+            let (fblock, mapped) = self.get_extent_blocks(block, end - block, false)?;
+            if fblock != 0 {
+                block += mapped.max(1);
+                continue;
+            }
+            let (fblock, mapped) = self.get_extent_blocks(block, end - block, true)?;
+            let mapped = mapped.max(1);
+            self.zero_blocks(fblock, mapped)?;
+            block += mapped;
+        }
+        Ok(())
+    }
+
+    /// Overwrites `count` blocks from `fblock` with zeros.
+    fn zero_blocks(&mut self, fblock: u64, count: u32) -> Ext4Result {
+        // Blocks per device write, bounding the size of the zero buffer
+        const CHUNK: u32 = 16;
+        let block_size = self.block_size() as usize;
+        let zeros = vec![0u8; count.min(CHUNK) as usize * block_size];
+        let bdev = unsafe { (*self.inner.fs).bdev };
+        let mut done = 0;
+        while done < count {
+            let cnt = (count - done).min(CHUNK);
+            let start = fblock + done as u64;
+            unsafe {
+                invalidate_cached(bdev, start, cnt);
+                ext4_blocks_set_direct(bdev, zeros.as_ptr() as _, start, cnt)
+                    .context("ext4_blocks_set_direct")?;
+            }
+            done += cnt;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at `offset` within `fblock` as a single whole-block
+    /// device write, rather than lwext4's read-modify-write of each partial
+    /// device block. The rest of the block is zeroed if `fresh`, so that no
+    /// stale disk contents show through in a newly mapped block, and kept
+    /// otherwise.
+    fn write_in_block(
+        &mut self,
+        fblock: u64,
+        offset: usize,
+        buf: &[u8],
+        fresh: bool,
+    ) -> Ext4Result<()> {
+        let block_size = self.block_size() as usize;
+        let start = fblock * block_size as u64;
+        let mut block = vec![0u8; block_size];
+        if !fresh {
+            self.read_bytes(start, &mut block)?;
+        }
+        block[offset..offset + buf.len()].copy_from_slice(buf);
+        self.write_bytes(start, &block)
+    }
+
+    pub(super) fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Ext4Result<()> {
         unsafe {
             let bdev = (*self.inner.fs).bdev;
             ext4_block_readbytes(bdev, offset, buf.as_mut_ptr() as _, buf.len() as _)
@@ -59,39 +238,197 @@ impl<Hal: SystemHal> InodeRef<Hal> {
     fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Ext4Result<()> {
         unsafe {
             let bdev = (*self.inner.fs).bdev;
+            let block_size = self.block_size() as u64;
+            let start = offset / block_size;
+            let end = (offset + buf.len() as u64).div_ceil(block_size);
+            invalidate_cached(bdev, start, (end - start) as u32);
             ext4_block_writebytes(bdev, offset, buf.as_ptr() as _, buf.len() as _)
                 .context("ext4_block_writebytes")
         }
     }
 
-    pub fn read_at(&mut self, mut buf: &mut [u8], pos: u64) -> Ext4Result<usize> {
+    pub fn read_at(&mut self, buf: &mut [u8], pos: u64) -> Ext4Result<usize> {
+        self.read_at_inner(buf, pos, None)
+    }
+
+    /// Like [`InodeRef::read_at`], but polls `cancel` before each device read
+    /// and fails with `EINTR` once it returns `true`. Data read before the
+    /// cancellation is discarded.
+    pub fn read_at_cancellable(
+        &mut self,
+        buf: &mut [u8],
+        pos: u64,
+        cancel: &dyn Fn() -> bool,
+    ) -> Ext4Result<usize> {
+        self.read_at_inner(buf, pos, Some(cancel))
+    }
+
+    /// Reads into each buffer in turn, starting at `pos`, as with `preadv`.
+    /// Stops early at the end of the file.
+    pub fn read_at_vectored(&mut self, bufs: &mut [&mut [u8]], mut pos: u64) -> Ext4Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            let read = self.read_at(buf, pos)?;
+            total += read;
+            pos += read as u64;
+            if read < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Writes each buffer in turn, starting at `pos`, as with `pwritev`.
+    pub fn write_at_vectored(&mut self, bufs: &[&[u8]], mut pos: u64) -> Ext4Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            let written = self.write_at(buf, pos)?;
+            total += written;
+            pos += written as u64;
+        }
+        Ok(total)
+    }
+
+    /// Like [`InodeRef::read_at`], but only serves data blocks already in the
+    /// block cache and fails with `EAGAIN` if any is missing, so the caller
+    /// can [`prefetch`](InodeRef::prefetch) and retry. Resolving the block
+    /// mapping may still read extent or indirect blocks.
+    pub fn read_at_nowait(&mut self, buf: &mut [u8], pos: u64) -> Ext4Result<usize> {
+        self.read_at_nowait_counted(buf, pos, &mut CacheCounters::default())
+    }
+    pub(crate) fn read_at_nowait_counted(
+        &mut self,
+        buf: &mut [u8],
+        pos: u64,
+        counters: &mut CacheCounters,
+    ) -> Ext4Result<usize> {
+        let file_size = self.size();
+        if pos >= file_size || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.has_inline_data() || self.is_inline_symlink() {
+            // Stored in the inode itself
+            return self.read_at(buf, pos);
+        }
+
+        let to_be_read = buf.len().min((file_size - pos) as usize);
+        let block_size = self.block_size() as u64;
+        let bc = unsafe { (*(*self.inner.fs).bdev).bc };
+        let last_block = ((pos + to_be_read as u64 - 1) / block_size) as u32;
+        let mut cache = ExtentCache::default();
+        let mut done = 0;
+        while done < to_be_read {
+            let cur = pos + done as u64;
+            let block = (cur / block_size) as u32;
+            let offset = (cur % block_size) as usize;
+            let len = (block_size as usize - offset).min(to_be_read - done);
+            let out = &mut buf[done..done + len];
+            match self.map_block(&mut cache, block, last_block - block + 1)? {
+                0 => out.fill(0),
+                fblock => {
+                    if !unsafe { read_cached(bc, fblock, offset, out) } {
+                        counters.misses += 1;
+                        return Err(Ext4Error::new(EAGAIN as _, "block not cached"));
+                    }
+                    counters.hits += 1;
+                }
+            }
+            done += len;
+        }
+        Ok(to_be_read)
+    }
+
+    /// Loads the data blocks backing `pos..pos + len` into the block cache,
+    /// for a later [`InodeRef::read_at_nowait`]. Blocks beyond the cache
+    /// capacity evict earlier ones.
+    pub fn prefetch(&mut self, pos: u64, len: u64) -> Ext4Result {
+        self.prefetch_counted(pos, len, &mut CacheCounters::default())
+    }
+    pub(crate) fn prefetch_counted(
+        &mut self,
+        pos: u64,
+        len: u64,
+        counters: &mut CacheCounters,
+    ) -> Ext4Result {
+        let end = pos.saturating_add(len).min(self.size());
+        if pos >= end || self.has_inline_data() {
+            return Ok(());
+        }
+        let block_size = self.block_size() as u64;
+        let bdev = unsafe { (*self.inner.fs).bdev };
+        let last_block = ((end - 1) / block_size) as u32;
+        let mut cache = ExtentCache::default();
+        for block in (pos / block_size) as u32..=last_block {
+            let fblock = self.map_block(&mut cache, block, last_block - block + 1)?;
+            if fblock == 0 {
+                continue;
+            }
+            if unsafe { read_cached((*bdev).bc, fblock, 0, &mut []) } {
+                counters.hits += 1;
+                continue;
+            }
+            counters.misses += 1;
+            unsafe {
+                let mut b: ext4_block = mem::zeroed();
+                ext4_block_get(bdev, &mut b, fblock).context("ext4_block_get")?;
+                ext4_block_set(bdev, &mut b).context("ext4_block_set")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_at_inner(
+        &mut self,
+        mut buf: &mut [u8],
+        pos: u64,
+        cancel: Option<&dyn Fn() -> bool>,
+    ) -> Ext4Result<usize> {
         unsafe {
             let file_size = self.size();
-            let block_size = get_block_size(self.superblock());
+            let block_size = self.block_size();
             let bdev = (*self.inner.fs).bdev;
 
+            // Clamping to EOF first keeps `pos + buf.len()` from overflowing
+            // below, whatever `pos` is
             if pos >= file_size || buf.is_empty() {
                 return Ok(0);
             }
             let to_be_read = buf.len().min((file_size - pos) as usize);
             buf = &mut buf[..to_be_read];
+            // Only reachable with a corrupted size, as writes stop at
+            // `max_file_size`
+            if (pos + to_be_read as u64 - 1) / block_size as u64 > u32::MAX as u64 {
+                return Err(Ext4Error::new(
+                    EFBIG as _,
+                    "offset past the last logical block",
+                ));
+            }
+
+            if self.has_inline_data() {
+                return self.read_inline(buf, pos);
+            }
 
             let inode = self.raw_inode();
 
             // symlink inline data
-            if self.inode_type() == InodeType::Symlink && file_size < size_of::<[u32; 15]>() as u64
-            {
+            if self.is_inline_symlink() {
                 let content = (inode as *const _ as *const u8).add(offset_of!(ext4_inode, blocks));
-                let buf = take_mut(&mut buf, (file_size - pos) as usize);
                 buf.copy_from_slice(slice::from_raw_parts(content.add(pos as usize), buf.len()));
+                return Ok(to_be_read);
             }
 
             let mut block_start = (pos / block_size as u64) as u32;
-            // This is inclusive!
-            let block_end = ((pos + buf.len() as u64).min(file_size) / block_size as u64) as u32;
+            // `buf` is already cut at EOF. Blocks before `block_end` are read
+            // whole, and only the part of `block_end` before `end` is left
+            // for the tail read; nothing is left when `end` is block-aligned,
+            // so `block_end` is never read past EOF.
+            let end = pos + buf.len() as u64;
+            let block_end = (end / block_size as u64) as u32;
 
             let offset = pos % block_size as u64;
             if offset > 0 {
+                // A read within one block ends here, leaving `block_start`
+                // past `block_end` and `buf` empty
                 let buf = take_mut(&mut buf, block_size as usize - offset as usize);
                 let fblock = self.get_inode_fblock(block_start)?;
                 if fblock != 0 {
@@ -113,12 +450,16 @@ impl<Hal: SystemHal> InodeRef<Hal> {
                 if count == 0 {
                     return Ok(());
                 }
+                if cancel.is_some_and(|cancel| cancel()) {
+                    return Err(Ext4Error::new(EINTR as _, "read cancelled"));
+                }
                 let buf = take_mut(buf, count as usize * block_size as usize);
                 ext4_blocks_get_direct(bdev, buf.as_mut_ptr() as _, start, count)
                     .context("ext4_blocks_get_direct")
             };
+            let mut cache = ExtentCache::default();
             for block in block_start..block_end {
-                let fblock = self.get_inode_fblock(block)?;
+                let fblock = self.map_block(&mut cache, block, block_end - block)?;
                 if fblock != fblock_start + fblock_count as u64 {
                     flush_fblock_segment(&mut buf, fblock_start, fblock_count)?;
                     fblock_start = fblock;
@@ -149,7 +490,47 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         }
     }
 
-    pub fn write_at(&mut self, mut buf: &[u8], pos: u64) -> Ext4Result<usize> {
+    /// Writes `buf` at `pos`, extending the file as needed. Only the blocks
+    /// the write touches are allocated, so a gap past the old end is left as
+    /// a hole. Immutable inodes fail with `EPERM`, and append-only ones are
+    /// always written at the end.
+    pub fn write_at(&mut self, buf: &[u8], pos: u64) -> Ext4Result<usize> {
+        self.write_at_prealloc(buf, pos, 0)
+    }
+
+    /// Like [`InodeRef::write_at`], but a write that extends the file first
+    /// maps at least `prealloc` new blocks past the old end, see
+    /// [`Ext4Filesystem::set_prealloc_blocks`].
+    ///
+    /// [`Ext4Filesystem::set_prealloc_blocks`]: crate::Ext4Filesystem::set_prealloc_blocks
+    pub(crate) fn write_at_prealloc(
+        &mut self,
+        mut buf: &[u8],
+        pos: u64,
+        prealloc: u32,
+    ) -> Ext4Result<usize> {
+        if self.is_immutable() {
+            return Err(Ext4Error::new(EPERM as _, "inode is immutable"));
+        }
+        let pos = if self.is_append_only() {
+            self.size()
+        } else {
+            pos
+        };
+        let Some(end) = pos.checked_add(buf.len() as u64) else {
+            return Err(Ext4Error::new(EINVAL as _, "offset overflow"));
+        };
+        // Logical block numbers are 32 bits wide even with `64bit`, which
+        // `max_file_size` accounts for
+        if end > max_file_size(self.superblock()) {
+            return Err(Ext4Error::new(EFBIG as _, "file too large"));
+        }
+        if self.has_inline_data() {
+            if self.fits_inline(pos + buf.len() as u64) {
+                return self.write_inline(buf, pos);
+            }
+            self.uninline()?;
+        }
         unsafe {
             let mut file_size = self.size();
             if pos > file_size {
@@ -158,24 +539,41 @@ impl<Hal: SystemHal> InodeRef<Hal> {
                 file_size = self.size();
             }
 
-            let block_size = get_block_size(self.superblock());
+            let block_size = self.block_size();
             let block_count = file_size.div_ceil(block_size as u64) as u32;
             let bdev = (*self.inner.fs).bdev;
 
             if buf.is_empty() {
                 return Ok(0);
             }
+
+            let end_count = (pos + buf.len() as u64).div_ceil(block_size as u64) as u32;
+            if prealloc > 0 && end_count > block_count {
+                self.prealloc_blocks(block_count, (end_count - block_count).max(prealloc))?;
+            }
             let to_be_written = buf.len();
 
             // TODO: symlink?
 
-            let get_fblock = |this: &mut Self, block: u32| -> Ext4Result<u64> {
+            let mut cache = ExtentCache::default();
+            // Also returns whether the block was newly mapped, in which case
+            // whatever the write doesn't cover must be zeroed
+            let mut get_fblock = |this: &mut Self, block: u32| -> Ext4Result<(u64, bool)> {
                 if block < block_count {
-                    this.init_inode_fblock(block)
+                    // Blocks that are already mapped never move, so only
+                    // holes need the allocating lookup
+                    match this.map_block(&mut cache, block, block_count - block)? {
+                        0 => Ok((this.init_inode_fblock(block)?, true)),
+                        fblock => Ok((fblock, false)),
+                    }
                 } else {
+                    // Blocks are visited in order, so past the old end each
+                    // one is the next to append. This includes a pure append
+                    // at a block-aligned EOF, where the first block is
+                    // `block_count` itself.
                     let (fblock, new_block) = this.append_inode_fblock()?;
                     assert_eq!(block, new_block);
-                    Ok(fblock)
+                    Ok((fblock, true))
                 }
             };
 
@@ -186,8 +584,8 @@ impl<Hal: SystemHal> InodeRef<Hal> {
             let offset = pos % block_size as u64;
             if offset > 0 {
                 let buf = take(&mut buf, block_size as usize - offset as usize);
-                let fblock = get_fblock(self, block_start)?;
-                self.write_bytes(fblock * block_size as u64 + offset, buf)?;
+                let (fblock, fresh) = get_fblock(self, block_start)?;
+                self.write_in_block(fblock, offset as usize, buf, fresh)?;
                 block_start += 1;
             }
 
@@ -199,11 +597,12 @@ impl<Hal: SystemHal> InodeRef<Hal> {
                     return Ok(());
                 }
                 let buf = take(buf, count as usize * block_size as usize);
+                invalidate_cached(bdev, start, count);
                 ext4_blocks_set_direct(bdev, buf.as_ptr() as _, start, count)
                     .context("ext4_blocks_set_direct")
             };
             for block in block_start..block_end {
-                let fblock = get_fblock(self, block)?;
+                let (fblock, _) = get_fblock(self, block)?;
                 if fblock != fblock_start + fblock_count as u64 {
                     flush_fblock_segment(&mut buf, fblock_start, fblock_count)?;
                     fblock_start = fblock;
@@ -211,93 +610,333 @@ impl<Hal: SystemHal> InodeRef<Hal> {
                 }
                 fblock_count += 1;
             }
+
             flush_fblock_segment(&mut buf, fblock_start, fblock_count)?;
 
             assert!(buf.len() < block_size as usize);
             if !buf.is_empty() {
-                let fblock = get_fblock(self, block_end)?;
-                self.write_bytes(fblock * block_size as u64, buf)?;
+                let (fblock, fresh) = get_fblock(self, block_end)?;
+                self.write_in_block(fblock, 0, buf, fresh)?;
             }
 
             let end = pos + to_be_written as u64;
             if end > file_size {
-                ext4_inode_set_size(self.inner.inode, end);
-                self.mark_dirty();
+                self.set_size(end);
             }
 
             Ok(to_be_written)
         }
     }
 
+    /// Shrinks the file to `size` bytes, freeing blocks past the new end.
+    ///
+    /// For indirect-mapped files lwext4 releases each indirect block once its
+    /// last entry is cleared, so the block count reported in
+    /// [`FileAttr::blocks`](super::FileAttr::blocks) covers mapping metadata
+    /// as well.
     pub fn truncate(&mut self, size: u64) -> Ext4Result<()> {
+        self.check_removable()?;
+        if self.has_inline_data() {
+            if self.fits_inline(size) {
+                return self.set_inline_len(size);
+            }
+            self.uninline()?;
+        }
+        let shrinking = size < self.size() && !self.is_inline_symlink();
         unsafe {
             let bdev = (*self.inner.fs).bdev;
             let _guard = WritebackGuard::new(bdev);
-            ext4_fs_truncate_inode(self.inner.as_mut(), size).context("ext4_fs_truncate_inode")
+            ext4_fs_truncate_inode(self.inner.as_mut(), size).context("ext4_fs_truncate_inode")?;
+        }
+
+        // Zero the rest of the new last block so that growing the file again
+        // doesn't expose the old contents
+        let block_size = self.block_size() as u64;
+        let offset = size % block_size;
+        if shrinking && offset != 0 {
+            let fblock = self.get_inode_fblock((size / block_size) as u32)?;
+            if fblock != 0 {
+                let zeros = vec![0; (block_size - offset) as usize];
+                self.write_bytes(fblock * block_size + offset, &zeros)?;
+            }
         }
+        Ok(())
     }
 
     pub fn set_symlink(&mut self, target: &[u8]) -> Ext4Result<()> {
-        let block_size = get_block_size(self.superblock());
+        let block_size = self.block_size();
         if target.len() > block_size as usize {
-            // ENAMETOOLONG
-            return 36.context("symlink too long");
+            return Err(Ext4Error::new(ENAMETOOLONG as _, "symlink too long"));
+        }
+
+        // Drop the previous target, freeing its block if it had one
+        if self.is_inline_symlink() {
+            unsafe { ext4_inode_set_size(self.inner.inode, 0) };
+        } else {
+            self.truncate(0)?;
         }
+        self.raw_inode_mut().blocks.fill(0);
 
         unsafe {
-            if target.len() < size_of::<u32>() * EXT4_INODE_BLOCKS as usize {
+            if target.len() < INLINE_DATA_LEN {
                 let ptr = (self.inner.inode as *mut u8).add(offset_of!(ext4_inode, blocks));
                 slice::from_raw_parts_mut(ptr, target.len()).copy_from_slice(target);
                 ext4_inode_clear_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS);
             } else {
                 ext4_fs_inode_blocks_init(self.inner.fs, self.inner.as_mut());
-                let mut fblock: u64 = 0;
-                let mut sblock: u32 = 0;
-                ext4_fs_append_inode_dblk(self.inner.as_mut(), &mut fblock, &mut sblock)
-                    .context("ext4_fs_append_inode_dblk")?;
+                let (fblock, _) = self.append_inode_fblock()?;
 
                 let off = fblock * block_size as u64;
                 self.write_bytes(off, target)?;
             }
             ext4_inode_set_size(self.inner.inode, target.len() as u64);
         }
+        self.mark_dirty();
 
         Ok(())
     }
 
+    /// Resizes the file. Growing it leaves a hole that reads back as zeros
+    /// without allocating blocks.
     pub fn set_len(&mut self, len: u64) -> Ext4Result<()> {
-        static EMPTY: [u8; 4096] = [0; 4096];
+        self.check_removable()?;
+
+        if len > max_file_size(self.superblock()) {
+            return Err(Ext4Error::new(EFBIG as _, "file too large"));
+        }
+
+        if self.has_inline_data() {
+            if self.fits_inline(len) {
+                return self.set_inline_len(len);
+            }
+            self.uninline()?;
+        }
 
         let cur_len = self.size();
         if len < cur_len {
             self.truncate(len)?;
         } else if len > cur_len {
-            // TODO: correct implementation
-            let block_size = get_block_size(self.superblock());
-            let old_blocks = cur_len.div_ceil(block_size as u64) as u32;
-            let new_blocks = len.div_ceil(block_size as u64) as u32;
-            for block in old_blocks..new_blocks {
-                let (fblock, new_block) = self.append_inode_fblock()?;
-                assert_eq!(block, new_block);
-                self.write_bytes(fblock * block_size as u64, &EMPTY[..block_size as usize])?;
+            // The new range is left as a hole. Only the rest of the old last
+            // block is zeroed, as it may hold stale bytes past the old end.
+            let block_size = self.block_size() as u64;
+            let offset = cur_len % block_size;
+            if offset != 0 {
+                let fblock = self.get_inode_fblock((cur_len / block_size) as u32)?;
+                if fblock != 0 {
+                    let zeros = vec![0; (block_size - offset) as usize];
+                    self.write_bytes(fblock * block_size + offset, &zeros)?;
+                }
             }
 
-            // Clear the last block extended part
-            let old_last_block = (cur_len / block_size as u64) as u32;
-            let old_block_start = (cur_len - (old_last_block as u64 * block_size as u64)) as usize;
-            let fblock = self.init_inode_fblock(old_last_block)?;
-            assert!(fblock != 0, "fblock should not be zero");
-            let length = block_size as usize - old_block_start;
-            self.write_bytes(
-                fblock * block_size as u64 + old_block_start as u64,
-                &EMPTY[..length],
-            )?;
-
-            unsafe {
-                ext4_inode_set_size(self.inner.inode, len);
-            }
-            self.mark_dirty();
+            self.set_size(len);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::cell::Cell;
+
+    use crate::{
+        ErrorKind, Features, FileAttr, InodeType, ROOT_INO,
+        error::EINTR,
+        ffi::{EFBIG, EINVAL},
+        mock::testing::*,
+    };
+
+    /// Leaves the free data blocks of `fs` filled with `0xff`.
+    fn dirty_free_blocks(fs: &mut TestFs) {
+        let ino = create_file(fs, "junk");
+        fs.write_at(ino, &vec![0xff; 1 << 20], 0).unwrap();
+        fs.unlink(ROOT_INO, "junk").unwrap();
+    }
+
+    #[test]
+    fn prealloc_keeps_interleaved_appends_contiguous() {
+        let (mut fs, _) = test_fs();
+        fs.set_prealloc_blocks(256);
+        let a = create_file(&mut fs, "a");
+        let b = create_file(&mut fs, "b");
+        let chunk = vec![1; 4096];
+        for i in 0..32 {
+            fs.write_at(a, &chunk, i * 4096).unwrap();
+            fs.write_at(b, &chunk, i * 4096).unwrap();
+        }
+        let extents = fs
+            .with_inode_ref(a, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        // At most split by a block group boundary
+        assert!(extents.len() <= 2, "{extents:?}");
+    }
+
+    #[test]
+    fn preallocated_blocks_read_as_zeros() {
+        let (mut fs, _) = test_fs();
+        dirty_free_blocks(&mut fs);
+        fs.set_prealloc_blocks(64);
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, b"hello", 0).unwrap();
+        // Both bring preallocated blocks inside EOF
+        fs.set_len(ino, 8192).unwrap();
+        fs.write_at(ino, b"world", 20000).unwrap();
+
+        let mut buf = vec![0xaa; 20005];
+        assert_eq!(fs.read_at(ino, &mut buf, 0).unwrap(), buf.len());
+        assert_eq!(&buf[..5], b"hello");
+        assert!(buf[5..20000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[20000..], b"world");
+    }
+
+    #[test]
+    fn tail_block_written_in_one_request() {
+        let (mut fs, dev) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, &vec![1; 8192], 0).unwrap();
+        fs.flush().unwrap();
+        let extents = fs
+            .with_inode_ref(ino, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        assert_eq!(extents.len(), 1);
+
+        // Four whole blocks and 700 bytes of the fifth: one request for the
+        // body and one for the whole tail block, where a byte-range write
+        // would also split the tail at the device block it ends in
+        dev.reset_counters();
+        fs.write_at(ino, &vec![2; 4 * 1024 + 700], 0).unwrap();
+        assert_eq!(dev.write_requests(), 2);
+
+        let buf = read_all(&mut fs, ino, 8192);
+        assert!(buf[..4 * 1024 + 700].iter().all(|&b| b == 2));
+        assert!(buf[4 * 1024 + 700..].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn long_symlink_is_name_too_long() {
+        let (mut fs, _) = test_fs();
+        let ino = fs
+            .create(ROOT_INO, "link", InodeType::Symlink, 0o777)
+            .unwrap();
+        let err = fs.set_symlink(ino, &[b'a'; 1025]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NameTooLong);
+    }
+
+    #[test]
+    fn past_max_file_size_is_efbig() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        let max = fs.max_file_size();
+        let err = fs.set_len(ino, max + 1).unwrap_err();
+        assert_eq!(err.code, EFBIG as i32);
+        let err = fs.write_at(ino, b"ab", max - 1).unwrap_err();
+        assert_eq!(err.code, EFBIG as i32);
+        let size = fs.with_inode_ref(ino, |inode| Ok(inode.size())).unwrap();
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn cancelled_read_is_eintr() {
+        let (mut fs, _) = test_fs();
+        let a = create_file(&mut fs, "a");
+        let b = create_file(&mut fs, "b");
+        // `b` in between leaves `a` in two segments
+        for i in 0..2 {
+            fs.write_at(a, &[1; 4096], i * 4096).unwrap();
+            fs.write_at(b, &[2; 4096], i * 4096).unwrap();
+        }
+        let extents = fs
+            .with_inode_ref(a, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        assert_eq!(extents.len(), 2, "{extents:?}");
+
+        let mut buf = vec![0; 8192];
+        let polls = Cell::new(0);
+        let cancel = || {
+            polls.set(polls.get() + 1);
+            polls.get() > 1
+        };
+        let err = fs.read_at_cancellable(a, &mut buf, 0, &cancel).unwrap_err();
+        assert_eq!(err.code, EINTR as i32);
+        assert_eq!(polls.get(), 2);
+
+        let read = fs.read_at_cancellable(a, &mut buf, 0, &|| false).unwrap();
+        assert_eq!(read, 8192);
+        assert!(buf.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn truncate_zeroes_tail_of_last_block() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        let block_size = fs.block_size() as usize;
+        fs.write_at(ino, &vec![0xff; block_size], 0).unwrap();
+        fs.with_inode_ref(ino, |inode| inode.truncate(100)).unwrap();
+        fs.set_len(ino, block_size as u64).unwrap();
+
+        let buf = read_all(&mut fs, ino, block_size);
+        assert!(buf[..100].iter().all(|&b| b == 0xff));
+        assert!(buf[100..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn write_near_max_file_size_on_64bit() {
+        let dev = mkfs();
+        patch_superblock(&dev, |sb| {
+            sb.features_incompatible |= u32::to_le(Features::INCOMPAT_64BIT);
+        });
+        let (mut fs, _) = mount(dev);
+        assert!(fs.features().has_64bit());
+        let ino = create_file(&mut fs, "file");
+        // In the last logical block
+        let max = fs.max_file_size();
+        assert!(max > 1 << 40);
+        assert_eq!(fs.write_at(ino, b"tail", max - 4).unwrap(), 4);
+        let mut buf = [0; 4];
+        assert_eq!(fs.read_at(ino, &mut buf, max - 4).unwrap(), 4);
+        assert_eq!(&buf, b"tail");
+        let size = fs.with_inode_ref(ino, |inode| Ok(inode.size())).unwrap();
+        assert_eq!(size, max);
+
+        let err = fs.write_at(ino, b"tail", max - 2).unwrap_err();
+        assert_eq!(err.code, EFBIG as i32);
+    }
+
+    #[test]
+    fn offsets_near_u64_max_fail_cleanly() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, b"hello", 0).unwrap();
+        let pos = u64::MAX - 10;
+
+        // Clamped to EOF, and `pos + buf.len()` would overflow on write
+        let mut buf = [0; 16];
+        assert_eq!(fs.read_at(ino, &mut buf, pos).unwrap(), 0);
+        let err = fs.write_at(ino, &buf, pos).unwrap_err();
+        assert_eq!(err.code, EINVAL as i32);
+        let err = fs.write_at(ino, &buf[..4], pos).unwrap_err();
+        assert_eq!(err.code, EFBIG as i32);
+        assert_eq!(read_all(&mut fs, ino, 5), b"hello");
+    }
+
+    #[test]
+    fn write_past_hole_allocates_one_block() {
+        let (mut fs, _) = test_fs();
+        let ino = create_file(&mut fs, "file");
+        let free = fs.stat().unwrap().free_blocks_count;
+        fs.write_at(ino, b"data", 1 << 30).unwrap();
+        assert_eq!(fs.stat().unwrap().free_blocks_count, free - 1);
+        let mut attr = FileAttr::default();
+        fs.get_attr(ino, &mut attr).unwrap();
+        assert_eq!(attr.blocks, fs.block_size() as u64 / 512);
+
+        let extents = fs
+            .with_inode_ref(ino, |inode| inode.fiemap(0, u64::MAX))
+            .unwrap();
+        assert_eq!(extents.len(), 2, "{extents:?}");
+        assert!(extents[0].is_hole);
+        let mut buf = [0xaa; 16];
+        fs.read_at(ino, &mut buf, 1 << 29).unwrap();
+        assert_eq!(buf, [0; 16]);
+    }
+}
@@ -0,0 +1,300 @@
+use core::{ptr, slice};
+
+use alloc::vec;
+
+use super::InodeRef;
+
+use crate::{Ext4Result, SystemHal, error::Context, ffi::*, util::inode_size};
+
+/// Size of `i_block`, which holds short symlink targets and the first bytes
+/// of inline data. The rest of inline data lives in the `system.data`
+/// extended attribute.
+pub(super) const INLINE_DATA_LEN: usize = EXT4_INODE_BLOCKS as usize * size_of::<u32>();
+
+const INLINE_XATTR_NAME: &str = "data";
+
+/// Magic number at the start of the in-inode extended attribute area.
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+/// Size of an extended attribute entry before its name.
+const XATTR_ENTRY_LEN: usize = 16;
+
+impl<Hal: SystemHal> InodeRef<Hal> {
+    pub fn has_inline_data(&self) -> bool {
+        unsafe { ext4_inode_has_flag(self.inner.inode, EXT4_INODE_FLAG_INLINE_DATA) }
+    }
+
+    fn inline_block(&mut self) -> &mut [u8] {
+        let blocks = &mut self.raw_inode_mut().blocks;
+        unsafe { slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, size_of_val(blocks)) }
+    }
+
+    /// Turns a freshly allocated inode into an empty inline-data inode.
+    pub(crate) fn init_inline(&mut self) -> Ext4Result {
+        self.inline_block().fill(0);
+        unsafe {
+            ext4_inode_clear_flag(self.inner.inode, EXT4_INODE_FLAG_EXTENTS);
+            ext4_inode_set_flag(self.inner.inode, EXT4_INODE_FLAG_INLINE_DATA);
+            ext4_xattr_set(
+                self.inner.as_mut(),
+                EXT4_XATTR_INDEX_SYSTEM as _,
+                INLINE_XATTR_NAME.as_ptr() as _,
+                INLINE_XATTR_NAME.len(),
+                ptr::null(),
+                0,
+            )
+            .context("ext4_xattr_set")?;
+        }
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Reads all inline data into `data`, which must be `size()` bytes long.
+    fn load_inline(&mut self, data: &mut [u8]) -> Ext4Result {
+        let head = data.len().min(INLINE_DATA_LEN);
+        data[..head].copy_from_slice(&self.inline_block()[..head]);
+        if data.len() > INLINE_DATA_LEN {
+            let tail = &mut data[INLINE_DATA_LEN..];
+            let mut len = 0;
+            unsafe {
+                ext4_xattr_get(
+                    self.inner.as_mut(),
+                    EXT4_XATTR_INDEX_SYSTEM as _,
+                    INLINE_XATTR_NAME.as_ptr() as _,
+                    INLINE_XATTR_NAME.len(),
+                    tail.as_mut_ptr() as _,
+                    tail.len(),
+                    &mut len,
+                )
+                .context("ext4_xattr_get")?;
+            }
+            let len = len.min(tail.len());
+            tail[len..].fill(0);
+        }
+        Ok(())
+    }
+
+    pub(super) fn read_inline(&mut self, buf: &mut [u8], pos: u64) -> Ext4Result<usize> {
+        let size = self.size() as usize;
+        let pos = pos as usize;
+        if pos >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - pos);
+        if size <= INLINE_DATA_LEN {
+            buf[..len].copy_from_slice(&self.inline_block()[pos..pos + len]);
+        } else {
+            let mut data = vec![0; size];
+            self.load_inline(&mut data)?;
+            buf[..len].copy_from_slice(&data[pos..pos + len]);
+        }
+        Ok(len)
+    }
+
+    /// Room for the `system.data` value in the extended attribute area after
+    /// the inode, counting the space the current value takes. Other
+    /// attributes there are left where they are. 128-byte inodes have no
+    /// such area.
+    fn inline_xattr_room(&self) -> usize {
+        let inode_size = inode_size(self.superblock()) as usize;
+        if inode_size <= EXT4_GOOD_OLD_INODE_SIZE as usize {
+            return 0;
+        }
+        let start =
+            EXT4_GOOD_OLD_INODE_SIZE as usize + u16::from_le(self.raw_inode().extra_isize) as usize;
+        if start >= inode_size {
+            return 0;
+        }
+        // The inode table block holds the whole on-disk inode
+        let area = unsafe {
+            slice::from_raw_parts(
+                (self.inner.inode as *const u8).add(start),
+                inode_size - start,
+            )
+        };
+        let entry_len = |name_len: usize| (XATTR_ENTRY_LEN + name_len).next_multiple_of(4);
+        let read_u32 = |off: usize| u32::from_le_bytes(area[off..off + 4].try_into().unwrap());
+
+        // Header, the entry for `system.data`, and the 4-byte end marker
+        let mut used = 4 + entry_len(INLINE_XATTR_NAME.len()) + 4;
+        if area.len() >= 4 && read_u32(0) == XATTR_MAGIC {
+            let mut off = 4;
+            while off + XATTR_ENTRY_LEN <= area.len() && read_u32(off) != 0 {
+                let name_len = area[off] as usize;
+                let index = area[off + 1] as u32;
+                let name_end = off + XATTR_ENTRY_LEN + name_len;
+                if name_end > area.len() {
+                    break;
+                }
+                let name = &area[off + XATTR_ENTRY_LEN..name_end];
+                if index != EXT4_XATTR_INDEX_SYSTEM || name != INLINE_XATTR_NAME.as_bytes() {
+                    let value_len = read_u32(off + 8) as usize;
+                    used += entry_len(name_len) + value_len.next_multiple_of(4);
+                }
+                off += entry_len(name_len);
+            }
+        }
+        // Values are stored padded to 4 bytes
+        area.len().saturating_sub(used) & !3
+    }
+
+    /// Whether a file of `len` bytes can be kept inline, in `i_block` and the
+    /// in-inode `system.data` attribute.
+    pub(super) fn fits_inline(&self, len: u64) -> bool {
+        len <= (INLINE_DATA_LEN + self.inline_xattr_room()) as u64
+    }
+
+    /// Replaces the inline data with `data`, setting the size to match.
+    fn store_inline(&mut self, data: &[u8]) -> Ext4Result {
+        let head = data.len().min(INLINE_DATA_LEN);
+        let block = self.inline_block();
+        block[..head].copy_from_slice(&data[..head]);
+        // Keep the unused part zeroed so that growing reads back zeros
+        block[head..].fill(0);
+        let tail = &data[head..];
+        unsafe {
+            ext4_xattr_set(
+                self.inner.as_mut(),
+                EXT4_XATTR_INDEX_SYSTEM as _,
+                INLINE_XATTR_NAME.as_ptr() as _,
+                INLINE_XATTR_NAME.len(),
+                tail.as_ptr() as _,
+                tail.len(),
+            )
+            .context("ext4_xattr_set")?;
+            ext4_inode_set_size(self.inner.inode, data.len() as u64);
+        }
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Writes to an inline-data inode. The caller must have checked
+    /// [`InodeRef::fits_inline`] for the resulting size.
+    pub(super) fn write_inline(&mut self, buf: &[u8], pos: u64) -> Ext4Result<usize> {
+        let size = self.size() as usize;
+        let pos = pos as usize;
+        let end = pos + buf.len();
+        if end <= INLINE_DATA_LEN && size <= INLINE_DATA_LEN {
+            // Only `i_block` changes
+            self.inline_block()[pos..end].copy_from_slice(buf);
+            if end > size {
+                unsafe { ext4_inode_set_size(self.inner.inode, end as u64) };
+            }
+            self.mark_dirty();
+            return Ok(buf.len());
+        }
+        let mut data = vec![0; size.max(end)];
+        self.load_inline(&mut data[..size])?;
+        data[pos..end].copy_from_slice(buf);
+        self.store_inline(&data)?;
+        Ok(buf.len())
+    }
+
+    /// Resizes an inline-data inode. The caller must have checked
+    /// [`InodeRef::fits_inline`] for `len`.
+    pub(super) fn set_inline_len(&mut self, len: u64) -> Ext4Result {
+        let size = self.size() as usize;
+        let len = len as usize;
+        if len <= INLINE_DATA_LEN && size <= INLINE_DATA_LEN {
+            // Keep the unused part zeroed so that growing reads back zeros
+            self.inline_block()[len..].fill(0);
+            unsafe { ext4_inode_set_size(self.inner.inode, len as u64) };
+            self.mark_dirty();
+            return Ok(());
+        }
+        let mut data = vec![0; size.max(len)];
+        self.load_inline(&mut data[..size])?;
+        self.store_inline(&data[..len])
+    }
+
+    /// Moves inline data out to regular data blocks.
+    pub(super) fn uninline(&mut self) -> Ext4Result {
+        let mut data = vec![0; self.size() as usize];
+        self.load_inline(&mut data)?;
+
+        unsafe {
+            let ret = ext4_xattr_remove(
+                self.inner.as_mut(),
+                EXT4_XATTR_INDEX_SYSTEM as _,
+                INLINE_XATTR_NAME.as_ptr() as _,
+                INLINE_XATTR_NAME.len(),
+            );
+            if ret != ENODATA as _ {
+                ret.context("ext4_xattr_remove")?;
+            }
+
+            self.inline_block().fill(0);
+            ext4_inode_clear_flag(self.inner.inode, EXT4_INODE_FLAG_INLINE_DATA);
+            ext4_inode_set_size(self.inner.inode, 0);
+            ext4_fs_inode_blocks_init(self.inner.fs, self.inner.as_mut());
+        }
+        self.mark_dirty();
+
+        self.write_at(&data, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::{Features, FileAttr, MockBlockDevice, mock::testing::*};
+
+    /// Mounts a fresh image with `inline_data` and inodes of `inode_size`
+    /// bytes.
+    fn inline_fs(inode_size: u32) -> (TestFs, MockBlockDevice) {
+        let dev = mkfs_with(|info| info.inode_size = inode_size);
+        patch_superblock(&dev, |sb| {
+            sb.features_incompatible |= u32::to_le(Features::INCOMPAT_INLINE_DATA);
+        });
+        mount(dev)
+    }
+
+    fn is_inline(fs: &mut TestFs, ino: u32) -> bool {
+        fs.with_inode_ref(ino, |inode| Ok(inode.has_inline_data()))
+            .unwrap()
+    }
+
+    fn blocks(fs: &mut TestFs, ino: u32) -> u64 {
+        let mut attr = FileAttr::default();
+        fs.get_attr(ino, &mut attr).unwrap();
+        attr.blocks
+    }
+
+    #[test]
+    fn inline_write_read_and_uninline() {
+        let (mut fs, dev) = inline_fs(256);
+        let ino = create_file(&mut fs, "file");
+        fs.write_at(ino, b"hello", 0).unwrap();
+        assert!(is_inline(&mut fs, ino));
+        assert_eq!(read_all(&mut fs, ino, 5), b"hello");
+
+        // Past `i_block`, into the in-inode attribute
+        let data: Vec<u8> = (0..100).collect();
+        fs.write_at(ino, &data[5..], 5).unwrap();
+        fs.write_at(ino, &data[..5], 0).unwrap();
+        assert!(is_inline(&mut fs, ino));
+        assert_eq!(blocks(&mut fs, ino), 0);
+        fs.flush().unwrap();
+        drop(fs);
+
+        let (mut fs, _) = mount(dev);
+        assert_eq!(read_all(&mut fs, ino, 100), data);
+        fs.set_len(ino, 80).unwrap();
+        assert_eq!(read_all(&mut fs, ino, 80), data[..80]);
+
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        fs.write_at(ino, &data, 0).unwrap();
+        assert!(!is_inline(&mut fs, ino));
+        assert_eq!(read_all(&mut fs, ino, 3000), data);
+    }
+
+    #[test]
+    fn small_inodes_are_not_inline() {
+        let (mut fs, _) = inline_fs(128);
+        let ino = create_file(&mut fs, "file");
+        assert!(!is_inline(&mut fs, ino));
+        fs.write_at(ino, b"hello", 0).unwrap();
+        assert_eq!(read_all(&mut fs, ino, 5), b"hello");
+    }
+}
@@ -1,18 +1,23 @@
 mod attr;
 mod dir;
+mod fiemap;
 mod file;
+mod inline;
 
 use alloc::boxed::Box;
-pub use attr::FileAttr;
-pub use dir::{DirEntry, DirLookupResult, DirReader};
+pub use attr::{FileAttr, TimeSpec, major, makedev, minor};
+pub use dir::{DirEntry, DirLookupResult, DirReader, OwnedDirEntry};
+pub use fiemap::Extent;
+pub(crate) use file::CacheCounters;
 
 use core::marker::PhantomData;
 
-use crate::{SystemHal, ffi::*};
+use crate::{Ext4Error, Ext4Result, SystemHal, ffi::*};
 
 /// Inode type.
 #[repr(u8)]
 #[derive(PartialEq, Default, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InodeType {
     #[default]
     Unknown = 0,
@@ -39,15 +44,17 @@ impl From<u8> for InodeType {
     }
 }
 
-#[repr(transparent)]
 pub struct InodeRef<Hal: SystemHal> {
     pub(crate) inner: Box<ext4_inode_ref>,
+    block_size: u32,
     _phantom: PhantomData<Hal>,
 }
 impl<Hal: SystemHal> InodeRef<Hal> {
-    pub(crate) fn new(inner: ext4_inode_ref) -> Self {
+    /// Wraps `inner`; `block_size` is the filesystem's cached block size.
+    pub(crate) fn new(inner: ext4_inode_ref, block_size: u32) -> Self {
         Self {
             inner: Box::new(inner),
+            block_size,
             _phantom: PhantomData,
         }
     }
@@ -56,6 +63,11 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         self.inner.index
     }
 
+    /// Filesystem block size in bytes.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
     pub(crate) fn superblock(&self) -> &ext4_sblock {
         unsafe { &(*self.inner.fs).sb }
     }
@@ -67,6 +79,20 @@ impl<Hal: SystemHal> InodeRef<Hal> {
         self.inner.dirty = true;
     }
 
+    /// Fails with `EMLINK` if the inode cannot take another link.
+    pub(crate) fn check_nlink_limit(&self) -> Ext4Result {
+        const DIR_LINK_MAX: u16 = 65000;
+        let max = if self.is_dir() {
+            DIR_LINK_MAX
+        } else {
+            u16::MAX
+        };
+        if self.nlink() >= max {
+            return Err(Ext4Error::new(EMLINK as _, "too many links"));
+        }
+        Ok(())
+    }
+
     pub(crate) fn inc_nlink(&mut self) {
         unsafe {
             ext4_fs_inode_links_count_inc(self.inner.as_mut());
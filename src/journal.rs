@@ -0,0 +1,42 @@
+use crate::{BlockDevice, Ext4Error, Ext4Filesystem, Ext4Result, Features, SystemHal, ffi::*};
+
+const JBD2_MAGIC: u32 = 0xC03B3998;
+
+/// State of the internal journal, returned by [`Ext4Filesystem::journal_info`].
+#[derive(Debug, Clone)]
+pub struct JournalInfo {
+    /// Journal length in journal blocks
+    pub size_blocks: u32,
+    /// Whether the journal holds transactions that must be replayed
+    pub needs_recovery: bool,
+    /// Sequence number of the first transaction expected in the log
+    pub sequence: u32,
+}
+
+impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
+    /// Reads the journal superblock. Returns `None` if the filesystem has no
+    /// internal journal.
+    pub fn journal_info(&mut self) -> Ext4Result<Option<JournalInfo>> {
+        let features = self.features();
+        let ino = u32::from_le(self.superblock().journal_inode_number);
+        if !features.has_journal() || ino == 0 {
+            return Ok(None);
+        }
+
+        // The journal superblock is big-endian
+        let mut sb = [0u8; 32];
+        if self.read_at(ino, &mut sb, 0)? < sb.len() {
+            return Err(Ext4Error::new(EIO as _, "journal superblock truncated"));
+        }
+        let field = |off: usize| u32::from_be_bytes(sb[off..off + 4].try_into().unwrap());
+        if field(0) != JBD2_MAGIC {
+            return Err(Ext4Error::new(EINVAL as _, "bad journal superblock magic"));
+        }
+
+        Ok(Some(JournalInfo {
+            size_blocks: field(16),
+            needs_recovery: features.has_incompat(Features::INCOMPAT_RECOVER) || field(28) != 0,
+            sequence: field(24),
+        }))
+    }
+}
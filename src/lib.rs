@@ -4,6 +4,8 @@
 #![feature(associated_type_defaults)]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 #[macro_use]
 extern crate log;
@@ -19,12 +21,32 @@ pub mod ffi {
 }
 
 mod blockdev;
+mod check;
 mod error;
+mod features;
 mod fs;
+mod handle;
 mod inode;
+mod journal;
+#[cfg(any(test, feature = "test-util"))]
+mod mock;
+mod path;
+mod scan;
+#[cfg(feature = "std")]
+mod sync;
 mod util;
 
 pub use blockdev::{BlockDevice, EXT4_DEV_BSIZE};
-pub use error::{Ext4Error, Ext4Result};
+pub use check::{CheckError, CheckReport};
+pub use error::{ErrorKind, Ext4Error, Ext4Result};
+pub use features::{DefaultMountOpts, Features};
 pub use fs::*;
+pub use handle::File;
 pub use inode::*;
+pub use journal::JournalInfo;
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::{MockBlockDevice, testing};
+pub use path::{MAX_SYMLINKS, ROOT_INO};
+pub use scan::InodeIter;
+#[cfg(feature = "std")]
+pub use sync::SyncExt4;
@@ -0,0 +1,277 @@
+use core::cell::RefCell;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+
+use crate::{BlockDevice, EXT4_DEV_BSIZE, Ext4Error, Ext4Result, ffi::EIO};
+
+struct MockState {
+    data: Vec<u8>,
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    read_requests: u64,
+    write_requests: u64,
+}
+
+/// In-memory [`BlockDevice`] that counts reads and writes per device block.
+///
+/// Clones share the same backing storage and counters, so a test can keep a
+/// handle around after moving the device into an [`crate::Ext4Filesystem`].
+#[derive(Clone)]
+pub struct MockBlockDevice {
+    state: Rc<RefCell<MockState>>,
+}
+
+impl MockBlockDevice {
+    /// Creates a zeroed device of `num_blocks` blocks.
+    pub fn new(num_blocks: u64) -> Self {
+        Self::from_vec(vec![0; num_blocks as usize * EXT4_DEV_BSIZE])
+    }
+
+    /// Creates a device holding a copy of `image`, zero-padded to a whole
+    /// number of blocks.
+    pub fn from_image(image: &[u8]) -> Self {
+        Self::from_vec(image.to_vec())
+    }
+
+    fn from_vec(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(EXT4_DEV_BSIZE), 0);
+        let blocks = data.len() / EXT4_DEV_BSIZE;
+        Self {
+            state: Rc::new(RefCell::new(MockState {
+                data,
+                reads: vec![0; blocks],
+                writes: vec![0; blocks],
+                read_requests: 0,
+                write_requests: 0,
+            })),
+        }
+    }
+
+    /// Returns a copy of the current device contents.
+    pub fn to_image(&self) -> Vec<u8> {
+        self.state.borrow().data.clone()
+    }
+
+    pub fn read_count(&self, block_id: u64) -> u32 {
+        self.state.borrow().reads[block_id as usize]
+    }
+    pub fn write_count(&self, block_id: u64) -> u32 {
+        self.state.borrow().writes[block_id as usize]
+    }
+
+    pub fn total_reads(&self) -> u64 {
+        self.state.borrow().reads.iter().map(|&n| n as u64).sum()
+    }
+    pub fn total_writes(&self) -> u64 {
+        self.state.borrow().writes.iter().map(|&n| n as u64).sum()
+    }
+
+    /// Number of [`BlockDevice::read_blocks`] calls, however many blocks
+    /// each covered.
+    pub fn read_requests(&self) -> u64 {
+        self.state.borrow().read_requests
+    }
+    /// Number of [`BlockDevice::write_blocks`] calls, however many blocks
+    /// each covered.
+    pub fn write_requests(&self) -> u64 {
+        self.state.borrow().write_requests
+    }
+
+    pub fn reset_counters(&self) {
+        let mut state = self.state.borrow_mut();
+        state.reads.fill(0);
+        state.writes.fill(0);
+        state.read_requests = 0;
+        state.write_requests = 0;
+    }
+
+    fn range(state: &MockState, block_id: u64, len: usize) -> Ext4Result<(usize, usize)> {
+        let start = block_id as usize * EXT4_DEV_BSIZE;
+        if !len.is_multiple_of(EXT4_DEV_BSIZE) || start + len > state.data.len() {
+            return Err(Ext4Error::new(EIO as _, "mock device access out of range"));
+        }
+        Ok((start, start + len))
+    }
+}
+
+impl BlockDevice for MockBlockDevice {
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Ext4Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let (start, end) = Self::range(&state, block_id, buf.len())?;
+        state.data[start..end].copy_from_slice(buf);
+        for block in start / EXT4_DEV_BSIZE..end / EXT4_DEV_BSIZE {
+            state.writes[block] += 1;
+        }
+        state.write_requests += 1;
+        Ok(buf.len())
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Ext4Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let (start, end) = Self::range(&state, block_id, buf.len())?;
+        buf.copy_from_slice(&state.data[start..end]);
+        for block in start / EXT4_DEV_BSIZE..end / EXT4_DEV_BSIZE {
+            state.reads[block] += 1;
+        }
+        state.read_requests += 1;
+        Ok(buf.len())
+    }
+
+    fn num_blocks(&self) -> Ext4Result<u64> {
+        Ok((self.state.borrow().data.len() / EXT4_DEV_BSIZE) as u64)
+    }
+}
+
+/// Fixtures for tests that need a formatted filesystem on a
+/// [`MockBlockDevice`].
+pub mod testing {
+    use core::{mem, ptr, slice};
+
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    use super::MockBlockDevice;
+    use crate::{
+        BlockDevice, DummyHal, EXT4_DEV_BSIZE, Ext4Filesystem, FsConfig, InodeType, ROOT_INO,
+        blockdev::Ext4BlockDevice, error::Context, ffi::*,
+    };
+
+    /// Size of the images made by [`mkfs`], 8 MiB in device blocks.
+    pub const IMAGE_BLOCKS: u64 = 16384;
+
+    pub type TestFs = Ext4Filesystem<DummyHal, MockBlockDevice>;
+
+    /// Formats a fresh ext4 image with 1 KiB blocks and no journal.
+    pub fn mkfs() -> MockBlockDevice {
+        mkfs_with(|_| {})
+    }
+
+    /// Like [`mkfs`], letting `f` adjust the parameters first.
+    pub fn mkfs_with(f: impl FnOnce(&mut ext4_mkfs_info)) -> MockBlockDevice {
+        let dev = MockBlockDevice::new(IMAGE_BLOCKS);
+        let mut bdev = Ext4BlockDevice::new(dev.clone()).unwrap();
+        unsafe {
+            let mut fs: Box<ext4_fs> = Box::new(mem::zeroed());
+            let mut info: ext4_mkfs_info = mem::zeroed();
+            info.block_size = 1024;
+            info.journal = false;
+            info.label = c"lwext4".as_ptr();
+            f(&mut info);
+            ext4_mkfs(fs.as_mut(), bdev.inner.as_mut(), &mut info, F_SET_EXT4 as _)
+                .context("ext4_mkfs")
+                .unwrap();
+        }
+        dev
+    }
+
+    /// Applies `f` to the superblock of the unmounted image in `dev`, then
+    /// updates its checksum.
+    pub fn patch_superblock(dev: &MockBlockDevice, f: impl FnOnce(&mut ext4_sblock)) {
+        const SB_OFFSET: usize = 1024;
+        let image = dev.to_image();
+        let mut sb: ext4_sblock =
+            unsafe { ptr::read_unaligned(image[SB_OFFSET..].as_ptr() as *const _) };
+        f(&mut sb);
+        let len = mem::offset_of!(ext4_sblock, checksum) as u32;
+        sb.checksum = u32::to_le(unsafe { ext4_crc32c(u32::MAX, &sb as *const _ as _, len) });
+        let bytes = unsafe {
+            slice::from_raw_parts(&sb as *const _ as *const u8, size_of::<ext4_sblock>())
+        };
+        dev.clone()
+            .write_blocks((SB_OFFSET / EXT4_DEV_BSIZE) as u64, bytes)
+            .unwrap();
+    }
+
+    /// Mounts `dev`, returning the filesystem along with a handle to the
+    /// device for inspecting its counters.
+    pub fn mount(dev: MockBlockDevice) -> (TestFs, MockBlockDevice) {
+        let fs = Ext4Filesystem::new(dev.clone(), FsConfig::default()).unwrap();
+        (fs, dev)
+    }
+
+    /// Mounts a freshly formatted image.
+    pub fn test_fs() -> (TestFs, MockBlockDevice) {
+        mount(mkfs())
+    }
+
+    /// Creates an empty regular file `name` in the root directory.
+    pub fn create_file(fs: &mut TestFs, name: &str) -> u32 {
+        fs.create(ROOT_INO, name, InodeType::RegularFile, 0o644)
+            .unwrap()
+    }
+
+    /// Reads the first `len` bytes of `ino`, which must all be there.
+    pub fn read_all(fs: &mut TestFs, ino: u32, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        assert_eq!(fs.read_at(ino, &mut buf, 0).unwrap(), len);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::*, *};
+    use crate::ROOT_INO;
+
+    #[test]
+    fn counts_per_block() {
+        let mut dev = MockBlockDevice::new(8);
+        let mut buf = vec![0xa5; 2 * EXT4_DEV_BSIZE];
+        dev.write_blocks(3, &buf).unwrap();
+        assert_eq!(
+            (0..8).map(|b| dev.write_count(b)).collect::<Vec<_>>(),
+            [0, 0, 0, 1, 1, 0, 0, 0]
+        );
+
+        buf.fill(0);
+        dev.read_blocks(4, &mut buf[..EXT4_DEV_BSIZE]).unwrap();
+        assert!(buf[..EXT4_DEV_BSIZE].iter().all(|&b| b == 0xa5));
+        assert_eq!((dev.read_count(3), dev.read_count(4)), (0, 1));
+
+        assert_eq!((dev.total_reads(), dev.total_writes()), (1, 2));
+        assert_eq!((dev.read_requests(), dev.write_requests()), (1, 1));
+        dev.reset_counters();
+        assert_eq!((dev.total_reads(), dev.total_writes()), (0, 0));
+    }
+
+    #[test]
+    fn clones_share_state() {
+        let dev = MockBlockDevice::new(4);
+        let mut other = dev.clone();
+        other.write_blocks(1, &[1; EXT4_DEV_BSIZE]).unwrap();
+        assert_eq!(dev.write_count(1), 1);
+        assert_eq!(dev.to_image()[EXT4_DEV_BSIZE], 1);
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        let mut dev = MockBlockDevice::new(4);
+        let mut buf = [0; EXT4_DEV_BSIZE];
+        assert!(dev.read_blocks(4, &mut buf).is_err());
+        assert!(dev.write_blocks(3, &[0; 2 * EXT4_DEV_BSIZE]).is_err());
+        assert!(dev.read_blocks(0, &mut buf[..100]).is_err());
+        assert_eq!(dev.total_reads() + dev.total_writes(), 0);
+    }
+
+    #[test]
+    fn from_image_pads() {
+        let dev = MockBlockDevice::from_image(&[7; 700]);
+        assert_eq!(dev.num_blocks().unwrap(), 2);
+        assert_eq!(dev.to_image()[699..701], [7, 0]);
+    }
+
+    #[test]
+    fn mounts_formatted_image() {
+        let (mut fs, dev) = test_fs();
+        assert!(dev.total_reads() > 0);
+        assert!(
+            fs.with_inode_ref(ROOT_INO, |inode| Ok(inode.is_dir()))
+                .unwrap()
+        );
+        assert_eq!(fs.block_size(), 1024);
+
+        // A copy of the image mounts on its own
+        fs.flush().unwrap();
+        let (mut copy, _) = mount(MockBlockDevice::from_image(&dev.to_image()));
+        assert_eq!(copy.stat().unwrap().blocks_count, fs.blocks_count());
+    }
+}
@@ -0,0 +1,234 @@
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{
+    BlockDevice, ErrorKind, Ext4Error, Ext4Filesystem, Ext4Result, FileAttr, InodeType,
+    OwnedDirEntry, SystemHal, error::ELOOP, ffi::*,
+};
+
+/// Inode number of the root directory.
+pub const ROOT_INO: u32 = 2;
+
+/// Maximum number of symlinks expanded by [`Ext4Filesystem::resolve`].
+pub const MAX_SYMLINKS: u32 = 40;
+
+impl<Hal: SystemHal, Dev: BlockDevice> Ext4Filesystem<Hal, Dev> {
+    /// Creates the directory at `path` along with any missing parents,
+    /// starting from the root directory. Returns the inode of the final
+    /// directory.
+    pub fn create_dir_all(&mut self, path: &str, mode: u32) -> Ext4Result<u32> {
+        let mut cur = ROOT_INO;
+        for name in components(path) {
+            let found = self
+                .lookup(cur, name)
+                .map(|mut result| result.entry().ino());
+            cur = match found {
+                Ok(ino) => {
+                    if !self.with_inode_ref(ino, |inode| Ok(inode.is_dir()))? {
+                        return Err(Ext4Error::new(ENOTDIR as _, None));
+                    }
+                    ino
+                }
+                Err(err) if err.is_not_found() => {
+                    self.create(cur, name, InodeType::Directory, mode)?
+                }
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(cur)
+    }
+
+    /// Removes `name` from `parent` along with everything below it. Symlinks
+    /// are removed themselves and never followed.
+    ///
+    /// The tree is walked with an explicit stack rather than recursion, so
+    /// its depth is bounded only by memory, and entry names are handled as
+    /// bytes, so non-UTF-8 names are removed like any other.
+    pub fn remove_dir_all(&mut self, parent: u32, name: &str) -> Ext4Result {
+        let ino = self.lookup(parent, name)?.entry().ino();
+        if self.with_inode_ref(ino, |inode| Ok(inode.is_dir()))? {
+            // Each frame holds a directory, its name in the directory below
+            // and its children not yet removed. Children are collected first,
+            // as removing entries invalidates the reader.
+            let mut stack = vec![(ino, Vec::new(), self.dir_children(ino)?)];
+            while let Some((dir, _, children)) = stack.last_mut() {
+                let dir = *dir;
+                if let Some(child) = children.pop() {
+                    if self.with_inode_ref(child.ino, |inode| Ok(inode.is_dir()))? {
+                        let grandchildren = self.dir_children(child.ino)?;
+                        stack.push((child.ino, child.name, grandchildren));
+                    } else {
+                        self.unlink_bytes(dir, &child.name)?;
+                    }
+                    continue;
+                }
+                let (_, name, _) = stack.pop().unwrap();
+                if let Some((up, ..)) = stack.last() {
+                    self.unlink_bytes(*up, &name)?;
+                }
+            }
+        }
+        self.unlink(parent, name)
+    }
+
+    fn dir_children(&mut self, ino: u32) -> Ext4Result<Vec<OwnedDirEntry>> {
+        self.read_dir(ino, 0)?.skip_dots()?.collect()
+    }
+
+    /// Resolves an absolute `path` to an inode number, starting from the root
+    /// directory.
+    ///
+    /// With `follow_symlinks`, symlinks met in intermediate components are
+    /// expanded, and so is the final component if `path` ends in `/`;
+    /// otherwise the final component is not followed. More than
+    /// [`MAX_SYMLINKS`] expansions fail with `ELOOP`.
+    pub fn resolve(&mut self, path: &str, follow_symlinks: bool) -> Ext4Result<u32> {
+        let mut pending: Vec<String> = components(path).rev().map(String::from).collect();
+        let trailing_slash = path.ends_with('/');
+        let mut cur = ROOT_INO;
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            if !self.with_inode_ref(cur, |inode| Ok(inode.is_dir()))? {
+                return Err(Ext4Error::new(ENOTDIR as _, None));
+            }
+            let child = self.lookup(cur, &name)?.entry().ino();
+            if follow_symlinks
+                && (trailing_slash || !pending.is_empty())
+                && self.with_inode_ref(child, |inode| Ok(inode.inode_type()))? == InodeType::Symlink
+            {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Ext4Error::new(ELOOP as _, None));
+                }
+                let target = self.read_link(child)?;
+                let target = core::str::from_utf8(&target)
+                    .map_err(|_| Ext4Error::new(EINVAL as _, "non-UTF-8 symlink target"))?;
+                if target.starts_with('/') {
+                    cur = ROOT_INO;
+                }
+                pending.extend(components(target).rev().map(String::from));
+                continue;
+            }
+            cur = child;
+        }
+        if trailing_slash && !self.with_inode_ref(cur, |inode| Ok(inode.is_dir()))? {
+            return Err(Ext4Error::new(ENOTDIR as _, None));
+        }
+        Ok(cur)
+    }
+
+    /// Whether `path` resolves to an inode, following symlinks in
+    /// intermediate components. A missing entry or a non-directory component
+    /// counts as absent; any other error is logged and also reported as
+    /// `false`.
+    pub fn exists(&mut self, path: &str) -> bool {
+        match self.resolve(path, true) {
+            Ok(_) => true,
+            Err(err) => {
+                if !matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotDirectory) {
+                    warn!("exists({path}) failed: {err:?}");
+                }
+                false
+            }
+        }
+    }
+
+    /// Fills `attr` for the inode at `path`, resolved like
+    /// [`Ext4Filesystem::exists`].
+    pub fn metadata(&mut self, path: &str, attr: &mut FileAttr) -> Ext4Result {
+        let ino = self.resolve(path, true)?;
+        self.get_attr(ino, attr)
+    }
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::*;
+
+    /// Builds `/a/b/f`, `/abs -> /a/b` and `/a/rel -> b`. Returns the inodes
+    /// of `b` and `f`.
+    fn tree(fs: &mut TestFs) -> (u32, u32) {
+        let b = fs.create_dir_all("/a/b", 0o755).unwrap();
+        let f = fs.create(b, "f", InodeType::RegularFile, 0o644).unwrap();
+        let a = fs.resolve("/a", false).unwrap();
+        for (parent, name, target) in [(ROOT_INO, "abs", "/a/b"), (a, "rel", "b")] {
+            let link = fs.create(parent, name, InodeType::Symlink, 0o777).unwrap();
+            fs.set_symlink(link, target.as_bytes()).unwrap();
+        }
+        (b, f)
+    }
+
+    #[test]
+    fn resolve_absolute_paths() {
+        let (mut fs, _) = test_fs();
+        let (b, f) = tree(&mut fs);
+        assert_eq!(fs.resolve("/", false).unwrap(), ROOT_INO);
+        assert_eq!(fs.resolve("/a/b/f", false).unwrap(), f);
+        assert_eq!(fs.resolve("//a///b", false).unwrap(), b);
+        let err = fs.resolve("/a/missing", false).unwrap_err();
+        assert_eq!(err.code, ENOENT as i32);
+    }
+
+    #[test]
+    fn resolve_trailing_slash() {
+        let (mut fs, _) = test_fs();
+        let (b, _) = tree(&mut fs);
+        assert_eq!(fs.resolve("/a/b/", false).unwrap(), b);
+        let err = fs.resolve("/a/b/f/", false).unwrap_err();
+        assert_eq!(err.code, ENOTDIR as i32);
+
+        // A trailing slash follows a final symlink to a directory
+        assert_eq!(fs.resolve("/abs/", true).unwrap(), b);
+        assert_eq!(fs.resolve("/a/rel/", true).unwrap(), b);
+        let err = fs.resolve("/abs/", false).unwrap_err();
+        assert_eq!(err.code, ENOTDIR as i32);
+    }
+
+    #[test]
+    fn resolve_symlinked_component() {
+        let (mut fs, _) = test_fs();
+        let (_, f) = tree(&mut fs);
+        assert_eq!(fs.resolve("/abs/f", true).unwrap(), f);
+        assert_eq!(fs.resolve("/a/rel/f", true).unwrap(), f);
+        let err = fs.resolve("/abs/f", false).unwrap_err();
+        assert_eq!(err.code, ENOTDIR as i32);
+
+        // The final component is not followed
+        let link = fs.lookup(ROOT_INO, "abs").unwrap().entry().ino();
+        assert_eq!(fs.resolve("/abs", true).unwrap(), link);
+
+        let looped = fs
+            .create(ROOT_INO, "loop", InodeType::Symlink, 0o777)
+            .unwrap();
+        fs.set_symlink(looped, b"/loop").unwrap();
+        let err = fs.resolve("/loop/f", true).unwrap_err();
+        assert_eq!(err.code, ELOOP as i32);
+    }
+
+    #[test]
+    fn exists_and_metadata() {
+        let (mut fs, _) = test_fs();
+        let (_, f) = tree(&mut fs);
+        fs.write_at(f, b"hello", 0).unwrap();
+
+        assert!(fs.exists("/a/b/f"));
+        assert!(fs.exists("/abs/f"));
+        let mut attr = FileAttr::default();
+        fs.metadata("/abs/f", &mut attr).unwrap();
+        assert_eq!(attr.ino, f);
+        assert_eq!(attr.size, 5);
+
+        assert!(!fs.exists("/a/b/missing"));
+        let err = fs.metadata("/a/b/missing", &mut attr).unwrap_err();
+        assert_eq!(err.code, ENOENT as i32);
+
+        // `f` is not a directory
+        assert!(!fs.exists("/a/b/f/x"));
+        let err = fs.metadata("/a/b/f/x", &mut attr).unwrap_err();
+        assert_eq!(err.code, ENOTDIR as i32);
+    }
+}
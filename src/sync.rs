@@ -0,0 +1,150 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use alloc::vec::Vec;
+
+use crate::{BlockDevice, Ext4Filesystem, Ext4Result, FileAttr, InodeType, StatFs, SystemHal};
+
+/// [`Ext4Filesystem`] behind a mutex, usable from several threads through
+/// `&self`.
+///
+/// lwext4 keeps all of a mounted filesystem's state in the structures owned
+/// by [`Ext4Filesystem`], and every access here goes through the mutex, so
+/// the raw pointers inside are never used by two threads at once. Inode
+/// references are only lent out to closures, and [`crate::DirReader`] and
+/// [`crate::DirLookupResult`] borrow the filesystem they point into, so none
+/// of them can be returned from [`SyncExt4::with`] and outlive the lock.
+pub struct SyncExt4<Hal: SystemHal, Dev: BlockDevice> {
+    inner: Mutex<Ext4Filesystem<Hal, Dev>>,
+}
+
+// SAFETY: see the type documentation; the device is the only state that may
+// be tied to a thread, hence the `Dev: Send` bound.
+unsafe impl<Hal: SystemHal, Dev: BlockDevice + Send> Send for SyncExt4<Hal, Dev> {}
+unsafe impl<Hal: SystemHal, Dev: BlockDevice + Send> Sync for SyncExt4<Hal, Dev> {}
+
+impl<Hal: SystemHal, Dev: BlockDevice> SyncExt4<Hal, Dev> {
+    pub fn new(fs: Ext4Filesystem<Hal, Dev>) -> Self {
+        Self {
+            inner: Mutex::new(fs),
+        }
+    }
+
+    pub fn into_inner(self) -> Ext4Filesystem<Hal, Dev> {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ext4Filesystem<Hal, Dev>> {
+        // A panic mid-operation leaves lwext4 no worse off than a crash would
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs `f` with exclusive access to the filesystem.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Ext4Filesystem<Hal, Dev>) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    pub fn read_at(&self, ino: u32, buf: &mut [u8], offset: u64) -> Ext4Result<usize> {
+        self.lock().read_at(ino, buf, offset)
+    }
+    pub fn write_at(&self, ino: u32, buf: &[u8], offset: u64) -> Ext4Result<usize> {
+        self.lock().write_at(ino, buf, offset)
+    }
+    pub fn set_len(&self, ino: u32, len: u64) -> Ext4Result {
+        self.lock().set_len(ino, len)
+    }
+    pub fn read_link(&self, ino: u32) -> Ext4Result<Vec<u8>> {
+        self.lock().read_link(ino)
+    }
+
+    /// Looks up `name` in `parent`, returning the inode number.
+    pub fn lookup(&self, parent: u32, name: &str) -> Ext4Result<u32> {
+        Ok(self.lock().lookup(parent, name)?.entry().ino())
+    }
+    pub fn get_attr(&self, ino: u32, attr: &mut FileAttr) -> Ext4Result {
+        self.lock().get_attr(ino, attr)
+    }
+
+    pub fn create(&self, parent: u32, name: &str, ty: InodeType, mode: u32) -> Ext4Result<u32> {
+        self.lock().create(parent, name, ty, mode)
+    }
+    pub fn link(&self, dir: u32, name: &str, child: u32) -> Ext4Result {
+        self.lock().link(dir, name, child)
+    }
+    pub fn unlink(&self, dir: u32, name: &str) -> Ext4Result {
+        self.lock().unlink(dir, name)
+    }
+    pub fn rename(&self, src_dir: u32, src_name: &str, dst_dir: u32, dst_name: &str) -> Ext4Result {
+        self.lock().rename(src_dir, src_name, dst_dir, dst_name)
+    }
+
+    pub fn stat(&self) -> Ext4Result<StatFs> {
+        self.lock().stat()
+    }
+    pub fn flush(&self) -> Ext4Result {
+        self.lock().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{format, sync::Arc, thread};
+
+    use alloc::{vec, vec::Vec};
+
+    use super::SyncExt4;
+    use crate::{
+        BlockDevice, DummyHal, EXT4_DEV_BSIZE, Ext4Filesystem, Ext4Result, FsConfig, InodeType,
+        ROOT_INO, mock::testing::mkfs,
+    };
+
+    /// `Send` device, as the mock is not.
+    struct VecDevice(Vec<u8>);
+    impl BlockDevice for VecDevice {
+        fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Ext4Result<usize> {
+            let start = block_id as usize * EXT4_DEV_BSIZE;
+            self.0[start..start + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Ext4Result<usize> {
+            let start = block_id as usize * EXT4_DEV_BSIZE;
+            buf.copy_from_slice(&self.0[start..start + buf.len()]);
+            Ok(buf.len())
+        }
+        fn num_blocks(&self) -> Ext4Result<u64> {
+            Ok((self.0.len() / EXT4_DEV_BSIZE) as u64)
+        }
+    }
+
+    #[test]
+    fn two_threads() {
+        let dev = VecDevice(mkfs().to_image());
+        let fs = Ext4Filesystem::<DummyHal, _>::new(dev, FsConfig::default()).unwrap();
+        let fs = Arc::new(SyncExt4::new(fs));
+
+        let workers = (0..2u8)
+            .map(|i| {
+                let fs = fs.clone();
+                thread::spawn(move || {
+                    let name = format!("file{i}");
+                    let ino = fs
+                        .create(ROOT_INO, &name, InodeType::RegularFile, 0o644)
+                        .unwrap();
+                    let data = vec![i; 10000];
+                    for round in 0..16 {
+                        fs.write_at(ino, &data, round * 1000).unwrap();
+                    }
+                    let mut buf = vec![0; data.len()];
+                    assert_eq!(fs.read_at(ino, &mut buf, 15000).unwrap(), data.len());
+                    assert_eq!(buf, data);
+                    (name, ino)
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            let (name, ino) = worker.join().unwrap();
+            assert_eq!(fs.lookup(ROOT_INO, &name).unwrap(), ino);
+        }
+    }
+}
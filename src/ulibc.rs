@@ -1,32 +1,27 @@
 mod uprint {
     use core::ffi::{c_char, c_int};
 
-    #[cfg(feature = "print")]
     #[linkage = "weak"]
     #[unsafe(no_mangle)]
     unsafe extern "C" fn printf(str: *const c_char, mut args: ...) -> c_int {
-        // extern "C" { pub fn printf(arg1: *const c_char, ...) -> c_int; }
         use printf_compat::{format, output};
 
         let mut s = alloc::string::String::new();
         let bytes_written =
             unsafe { format(str as _, args.as_va_list(), output::fmt_write(&mut s)) };
-        //println!("{}", s);
-        info!("{}", s);
+        info!("[lwext4] {}", s.trim_end());
 
         bytes_written
     }
 
-    #[cfg(not(feature = "print"))]
-    #[linkage = "weak"]
-    #[unsafe(no_mangle)]
-    unsafe extern "C" fn printf(str: *const c_char, _args: ...) -> c_int {
-        use core::ffi::CStr;
-        let c_str = unsafe { CStr::from_ptr(str) };
-        //let arg1 = args.arg::<usize>();
-
-        info!("[lwext4] {:?}", c_str);
-        0
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn printf_formats_arguments() {
+            // The raw format string would be one byte longer
+            let written = unsafe { super::printf(c"error %d\n".as_ptr(), 5) };
+            assert_eq!(written, "error 5\n".len() as _);
+        }
     }
 }
 
@@ -1,9 +1,93 @@
-use crate::ffi::ext4_sblock;
+use crate::{
+    Features,
+    ffi::{ext4_bgroup, ext4_sblock},
+};
 
 pub fn get_block_size(sb: &ext4_sblock) -> u32 {
     1024u32 << u32::from_le(sb.log_block_size)
 }
 
+/// On-disk inode size, as `ext4_get_sb_inode_size` computes it. Revision 0
+/// filesystems leave the field zero and always use 128-byte inodes.
+pub fn inode_size(sb: &ext4_sblock) -> u16 {
+    if u32::from_le(sb.rev_level) == 0 {
+        128
+    } else {
+        u16::from_le(sb.inode_size)
+    }
+}
+
+/// Largest file size addressable on this filesystem.
+pub fn max_file_size(sb: &ext4_sblock) -> u64 {
+    let features = Features::from_sblock(sb);
+    let block_size = get_block_size(sb) as u64;
+    // Logical block numbers are 32 bits wide
+    let mut blocks = u32::MAX as u64;
+    if !features.has_extents() {
+        let per_block = block_size / 4;
+        blocks = blocks.min(12 + per_block + per_block.pow(2) + per_block.pow(3));
+    }
+    if !features.has_huge_file() {
+        // `i_blocks` counts 512-byte sectors in 32 bits
+        blocks = blocks.min(u32::MAX as u64 / (block_size / 512));
+    }
+    blocks * block_size
+}
+
 pub fn revision_tuple(sb: &ext4_sblock) -> (u32, u16) {
     (u32::from_le(sb.rev_level), u16::from_le(sb.minor_rev_level))
 }
+
+/// Size of a group descriptor, as `ext4_sb_get_desc_size` computes it.
+pub fn desc_size(sb: &ext4_sblock) -> u32 {
+    u16::from_le(sb.desc_size).max(32) as u32
+}
+
+pub fn inode_bitmap_block(sb: &ext4_sblock, bg: &ext4_bgroup) -> u64 {
+    let mut block = u32::from_le(bg.inode_bitmap_lo) as u64;
+    if u16::from_le(sb.desc_size) > 32 {
+        block |= (u32::from_le(bg.inode_bitmap_hi) as u64) << 32;
+    }
+    block
+}
+
+pub fn block_bitmap_block(sb: &ext4_sblock, bg: &ext4_bgroup) -> u64 {
+    let mut block = u32::from_le(bg.block_bitmap_lo) as u64;
+    if u16::from_le(sb.desc_size) > 32 {
+        block |= (u32::from_le(bg.block_bitmap_hi) as u64) << 32;
+    }
+    block
+}
+
+pub fn inode_table_block(sb: &ext4_sblock, bg: &ext4_bgroup) -> u64 {
+    let mut block = u32::from_le(bg.inode_table_first_block_lo) as u64;
+    if u16::from_le(sb.desc_size) > 32 {
+        block |= (u32::from_le(bg.inode_table_first_block_hi) as u64) << 32;
+    }
+    block
+}
+
+pub fn first_ino(sb: &ext4_sblock) -> u32 {
+    if u32::from_le(sb.rev_level) == 0 {
+        // EXT4_GOOD_OLD_FIRST_INO
+        11
+    } else {
+        u32::from_le(sb.first_inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+
+    use super::*;
+
+    #[test]
+    fn inode_size_of_revision_0_is_128() {
+        let mut sb: ext4_sblock = unsafe { mem::zeroed() };
+        assert_eq!(inode_size(&sb), 128);
+        sb.rev_level = u32::to_le(1);
+        sb.inode_size = u16::to_le(256);
+        assert_eq!(inode_size(&sb), 256);
+    }
+}